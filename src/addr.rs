@@ -0,0 +1,266 @@
+//! Where a tunnel forwards to.
+
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use url::Url;
+
+use crate::{Error, ErrorKind, Result};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The upstream of a tunnel, in the forms the agent takes and reports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Addr {
+    /// A port on localhost, e.g. `8069`.
+    Port(u16),
+    /// e.g. `localhost:8069` or `[::1]:22`.
+    HostPort(String, u16),
+    /// `http://` or `https://` upstream, e.g. a local service speaking TLS.
+    Url(Url),
+    /// Static files served by the agent itself (`file:///path`). The path
+    /// is absolute: the agent resolves relative ones against its own
+    /// working directory.
+    File(PathBuf),
+    /// A form this crate does not know, kept as the agent reported it.
+    Other(String),
+}
+
+impl Addr {
+    /// The equivalent form the agent reports back: ports become
+    /// `localhost:port`, plain http and tcp URLs become `host:port`.
+    pub fn normalize(&self) -> Addr {
+        match self {
+            Addr::Port(port) => Addr::HostPort("localhost".to_owned(), *port),
+            Addr::HostPort(host, port) => Addr::HostPort(host.to_ascii_lowercase(), *port),
+            Addr::Url(url) if url.scheme() == "http" && url.path() == "/" => {
+                match (url.host_str(), url.port_or_known_default()) {
+                    (Some(host), Some(port)) => Addr::HostPort(host.to_owned(), port),
+                    _ => self.clone(),
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// Host and port to connect to, if the upstream is a network service.
+    pub fn host_port(&self) -> Option<(String, u16)> {
+        match self {
+            Addr::Port(port) => Some(("localhost".to_owned(), *port)),
+            Addr::HostPort(host, port) => Some((host.clone(), *port)),
+            Addr::Url(url) => Some((
+                url.host_str()?
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .to_owned(),
+                url.port_or_known_default()?,
+            )),
+            Addr::File(_) | Addr::Other(_) => None,
+        }
+    }
+
+    /// Whether something accepts connections on the upstream port (or, for
+    /// files, whether the path exists).
+    pub fn is_reachable(&self) -> bool {
+        match self {
+            Addr::File(path) => path.exists(),
+            _ => self
+                .socket_addrs()
+                .iter()
+                .any(|addr| TcpStream::connect_timeout(addr, CONNECT_TIMEOUT).is_ok()),
+        }
+    }
+
+    pub(crate) fn socket_addrs(&self) -> Vec<SocketAddr> {
+        let (host, port) = match self.host_port() {
+            Some(host_port) => host_port,
+            None => return Vec::new(),
+        };
+        let host = host.trim_matches(|c| c == '[' || c == ']');
+        (host, port)
+            .to_socket_addrs()
+            .map(Iterator::collect)
+            .unwrap_or_default()
+    }
+}
+
+fn invalid(text: &str) -> Error {
+    ErrorKind::InvalidSpec(format!("{:?} is not a tunnel address", text)).into()
+}
+
+impl FromStr for Addr {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        if !text.is_empty() && text.chars().all(|c| c.is_ascii_digit()) {
+            return text.parse().map(Addr::Port).map_err(|_| invalid(text));
+        }
+        if let Some(path) = text.strip_prefix("file://") {
+            let url = Url::parse(text).map_err(|_| invalid(text))?;
+            // `file://srv/www` names a host, not a relative path
+            return match url.to_file_path() {
+                Ok(path) => Ok(Addr::File(path)),
+                Err(()) if Path::new(path).is_absolute() => Ok(Addr::File(PathBuf::from(path))),
+                Err(()) => Err(invalid(text)),
+            };
+        }
+        if text.contains("://") {
+            let url = Url::parse(text).map_err(|_| invalid(text))?;
+            return match url.scheme() {
+                "http" | "https" if url.host_str().is_some() => Ok(Addr::Url(url)),
+                "tcp" | "tls" => match (url.host_str(), url.port()) {
+                    (Some(host), Some(port)) => Ok(Addr::HostPort(host.to_owned(), port)),
+                    _ => Err(invalid(text)),
+                },
+                _ => Err(invalid(text)),
+            };
+        }
+        match text.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && !host.contains(char::is_whitespace) => {
+                let port = port.parse().map_err(|_| invalid(text))?;
+                Ok(Addr::HostPort(host.to_owned(), port))
+            }
+            _ => Err(invalid(text)),
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Port(port) => write!(f, "{}", port),
+            Addr::HostPort(host, port) => write!(f, "{}:{}", host, port),
+            Addr::Url(url) => write!(f, "{}", url),
+            Addr::File(path) => match Url::from_file_path(path) {
+                Ok(url) => write!(f, "{}", url),
+                Err(_) => write!(f, "file://{}", path.display()),
+            },
+            Addr::Other(addr) => f.write_str(addr),
+        }
+    }
+}
+
+impl From<u16> for Addr {
+    fn from(port: u16) -> Self {
+        Addr::Port(port)
+    }
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Self {
+        let host = match addr {
+            SocketAddr::V4(addr) => addr.ip().to_string(),
+            SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+        };
+        Addr::HostPort(host, addr.port())
+    }
+}
+
+impl From<Url> for Addr {
+    fn from(url: Url) -> Self {
+        Addr::Url(url)
+    }
+}
+
+/// Relative paths are resolved against the current directory.
+impl From<&Path> for Addr {
+    fn from(path: &Path) -> Self {
+        Addr::File(std::path::absolute(path).unwrap_or_else(|_| path.to_owned()))
+    }
+}
+
+impl From<PathBuf> for Addr {
+    fn from(path: PathBuf) -> Self {
+        Addr::from(path.as_path())
+    }
+}
+
+impl Serialize for Addr {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// Config files may give a bare port number. Strings this crate cannot
+/// parse become `Addr::Other`, so a tunnel list from a newer agent still
+/// loads.
+impl<'de> Deserialize<'de> for Addr {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Value::deserialize(deserializer)? {
+            Value::String(addr) => Ok(addr.parse().unwrap_or(Addr::Other(addr))),
+            Value::Number(port) => port.to_string().parse().map_err(de::Error::custom),
+            other => Err(de::Error::custom(format!("bad addr {}", other))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Addr;
+    use std::env;
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn parse_and_normalize() {
+        let erp: Addr = "8069".parse().unwrap();
+        assert_eq!(erp, Addr::Port(8069));
+        let reported: Addr = "http://localhost:8069".parse().unwrap();
+        assert_eq!(reported.normalize(), erp.normalize());
+        assert_eq!(
+            "tcp://localhost:22".parse::<Addr>().unwrap(),
+            Addr::HostPort("localhost".to_owned(), 22)
+        );
+        let tls: Addr = "https://localhost:8443".parse().unwrap();
+        assert_eq!(tls.normalize(), tls);
+        assert_eq!(tls.host_port(), Some(("localhost".to_owned(), 8443)));
+        let files: Addr = "file:///srv/www".parse().unwrap();
+        assert_eq!(files, Addr::File(PathBuf::from("/srv/www")));
+        assert_eq!(files.to_string(), "file:///srv/www");
+        let relative = Addr::from(Path::new("www"));
+        assert_eq!(
+            relative,
+            Addr::File(env::current_dir().unwrap().join("www"))
+        );
+        assert!(relative.to_string().starts_with("file:///"));
+        for bad in &[
+            "file://srv/www",
+            "",
+            "localhost",
+            "99999",
+            "ftp://localhost:21",
+            "local host:1",
+        ] {
+            assert!(bad.parse::<Addr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn unknown_reported_forms() {
+        let reported: Addr = serde_json::from_str(r#""unix:///run/app.sock""#).unwrap();
+        assert_eq!(reported, Addr::Other("unix:///run/app.sock".to_owned()));
+        assert_eq!(reported.to_string(), "unix:///run/app.sock");
+        assert_eq!(reported.host_port(), None);
+        assert!(serde_json::from_str::<Addr>("99999").is_err());
+    }
+
+    #[test]
+    fn reachability() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = Addr::from(listener.local_addr().unwrap());
+        assert!(addr.is_reachable());
+        drop(listener);
+        assert!(!addr.is_reachable());
+    }
+}
@@ -0,0 +1,358 @@
+//! Versioned agent API models.
+//!
+//! The v2 and v3 agents take different tunnel payloads and config files.
+//! Each version gets its own request/response models here; the rest of the
+//! crate works with `TunnelSpec` and the common `Tunnel` view and picks the
+//! models from the detected `AgentVersion`.
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+use crate::{
+    AgentVersion, ErrorKind, Proto, Result, ResultExt, TlsTermination, Tunnel, TunnelSpec,
+};
+
+pub mod v2;
+pub mod v3;
+
+/// The `api/tunnels` payload creating `spec` on an agent of `version`.
+pub fn tunnel_request(version: AgentVersion, spec: &TunnelSpec) -> Result<Value> {
+    if let Some(termination) = &spec.tls_termination {
+        if spec.proto != Proto::Tls {
+            return Err(ErrorKind::InvalidSpec(format!(
+                "tunnel {}: TLS termination needs a tls tunnel",
+                spec.name
+            ))
+            .into());
+        }
+        termination.check()?;
+    }
+    if let Some(host_header) = &spec.host_header {
+        host_header.check()?;
+    }
+    spec.request_header.check()?;
+    spec.response_header.check()?;
+    if let Some(restriction) = &spec.ip_restriction {
+        restriction.check()?;
+    }
+    if spec.remote_addr.is_some() && spec.proto != Proto::Tcp {
+        return Err(ErrorKind::InvalidSpec(format!(
+            "tunnel {}: remote_addr needs a tcp tunnel",
+            spec.name
+        ))
+        .into());
+    }
+    let value = match version {
+        AgentVersion::V2 => serde_json::to_value(v2::TunnelRequest::try_from(spec)?),
+        AgentVersion::V3 => {
+            if let Some(policy) = &spec.traffic_policy {
+                policy.validate()?;
+            }
+            serde_json::to_value(v3::TunnelRequest::from(spec))
+        }
+    };
+    value.chain_err(|| format!("could not serialize tunnel {}", spec.name))
+}
+
+/// The common view of a tunnel returned by an agent of `version`.
+pub fn parse_tunnel(version: AgentVersion, value: Value) -> Result<Tunnel> {
+    let tunnel = match version {
+        AgentVersion::V2 => serde_json::from_value::<v2::TunnelResponse>(value).map(Tunnel::from),
+        AgentVersion::V3 => serde_json::from_value::<v3::TunnelResponse>(value).map(Tunnel::from),
+    };
+    tunnel.chain_err(|| "malformed tunnel")
+}
+
+/// Read back from a config file as written, unchecked.
+fn tls_termination(
+    crt: Option<PathBuf>,
+    key: Option<PathBuf>,
+    client_cas: Option<PathBuf>,
+) -> Option<TlsTermination> {
+    Some(TlsTermination {
+        crt: crt?,
+        key: key?,
+        client_cas,
+    })
+}
+
+/// Settings written to an agent config file (`ngrok.yml`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AgentConfig {
+    pub authtoken: Option<String>,
+    pub web_addr: Option<String>,
+    pub tunnels: Vec<TunnelSpec>,
+}
+
+impl AgentConfig {
+    /// The config document understood by an agent of `version`.
+    pub fn to_value(&self, version: AgentVersion) -> Result<Value> {
+        let mut tunnels = BTreeMap::new();
+        for spec in &self.tunnels {
+            let mut entry = tunnel_request(version, spec)?;
+            // the name is the key of the entry
+            if let Value::Object(ref mut map) = entry {
+                map.remove("name");
+            }
+            tunnels.insert(spec.name.clone(), entry);
+        }
+        let value = match version {
+            AgentVersion::V2 => serde_json::to_value(v2::Config {
+                authtoken: self.authtoken.clone(),
+                web_addr: self.web_addr.clone(),
+                tunnels,
+            }),
+            AgentVersion::V3 => serde_json::to_value(v3::Config {
+                version: v3::CONFIG_VERSION.to_owned(),
+                authtoken: self.authtoken.clone(),
+                web_addr: self.web_addr.clone(),
+                tunnels,
+            }),
+        };
+        value.chain_err(|| "could not serialize agent config")
+    }
+
+    pub fn to_yaml(&self, version: AgentVersion) -> Result<String> {
+        serde_yaml::to_string(&self.to_value(version)?).chain_err(|| "could not write agent config")
+    }
+
+    /// Read back a config document of an agent of `version`. Settings this
+    /// model does not know are ignored.
+    pub fn from_value(version: AgentVersion, value: Value) -> Result<Self> {
+        let (authtoken, web_addr, entries) = match version {
+            AgentVersion::V2 => {
+                let config: v2::Config =
+                    serde_json::from_value(value).chain_err(|| "malformed agent config")?;
+                (config.authtoken, config.web_addr, config.tunnels)
+            }
+            AgentVersion::V3 => {
+                let config: v3::Config =
+                    serde_json::from_value(value).chain_err(|| "malformed agent config")?;
+                (config.authtoken, config.web_addr, config.tunnels)
+            }
+        };
+        let mut tunnels = Vec::new();
+        for (name, mut entry) in entries {
+            if let Value::Object(ref mut map) = entry {
+                map.insert("name".to_owned(), Value::String(name.clone()));
+            }
+            let spec = match version {
+                AgentVersion::V2 => {
+                    serde_json::from_value::<v2::TunnelRequest>(entry).map(TunnelSpec::from)
+                }
+                AgentVersion::V3 => {
+                    serde_json::from_value::<v3::TunnelRequest>(entry).map(TunnelSpec::from)
+                }
+            };
+            tunnels.push(spec.chain_err(|| format!("malformed tunnel {}", name))?);
+        }
+        Ok(AgentConfig {
+            authtoken,
+            web_addr,
+            tunnels,
+        })
+    }
+
+    pub fn from_yaml(version: AgentVersion, text: &str) -> Result<Self> {
+        let value = serde_yaml::from_str(text).chain_err(|| "malformed agent config")?;
+        Self::from_value(version, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_tunnel, tunnel_request, AgentConfig};
+    use crate::{
+        tunnel_fixture, Addr, AgentVersion, BindTls, Credentials, ErrorKind, Header, HeaderRules,
+        HostHeader, IpRestriction, OAuth, Proto, TlsTermination, TunnelSpec,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn tunnel_requests() {
+        let spec = TunnelSpec::http("erp", 8069)
+            .bind_tls(BindTls::Both)
+            .inspect(true);
+        assert_eq!(
+            tunnel_request(AgentVersion::V2, &spec).unwrap(),
+            json!({"name": "erp", "proto": "http", "addr": "8069", "bind_tls": "both", "inspect": true})
+        );
+        assert_eq!(
+            tunnel_request(AgentVersion::V3, &spec).unwrap(),
+            json!({"name": "erp", "proto": "http", "addr": "8069", "schemes": ["https", "http"], "inspect": true})
+        );
+    }
+
+    #[test]
+    fn auth_requests() {
+        let alice = Credentials::new("alice", "s3cret-enough").unwrap();
+        let bob = Credentials::new("bob", "another-secret").unwrap();
+        let spec = TunnelSpec::http("erp", 8069).basic_auth(alice.clone());
+        assert_eq!(
+            tunnel_request(AgentVersion::V2, &spec).unwrap()["auth"],
+            json!("alice:s3cret-enough")
+        );
+        let spec = spec.basic_auth(bob).oauth(
+            OAuth::new("google")
+                .unwrap()
+                .allow_domains(["example.com"])
+                .unwrap(),
+        );
+        let err = tunnel_request(AgentVersion::V2, &spec).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidSpec(_)));
+        let request = tunnel_request(AgentVersion::V3, &spec).unwrap();
+        assert_eq!(
+            request["basic_auth"],
+            json!(["alice:s3cret-enough", "bob:another-secret"])
+        );
+        assert_eq!(
+            request["oauth"],
+            json!({"provider": "google", "allow_domains": ["example.com"]})
+        );
+    }
+
+    #[test]
+    fn tls_requests() {
+        let dir = [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "tls"]
+            .iter()
+            .collect::<std::path::PathBuf>();
+        let termination = TlsTermination::new(dir.join("device.crt"), dir.join("device.key"))
+            .unwrap()
+            .client_cas(dir.join("clients-ca.crt"))
+            .unwrap();
+        let spec = TunnelSpec::tls("ota", 8443).tls_termination(termination);
+        let v2 = tunnel_request(AgentVersion::V2, &spec).unwrap();
+        assert_eq!(v2["proto"], "tls");
+        assert!(v2["client_cas"]
+            .as_str()
+            .unwrap()
+            .ends_with("clients-ca.crt"));
+        let v3 = tunnel_request(AgentVersion::V3, &spec).unwrap();
+        assert!(v3["mutual_tls_cas"]
+            .as_str()
+            .unwrap()
+            .ends_with("clients-ca.crt"));
+        let config = AgentConfig {
+            tunnels: vec![spec.clone()],
+            ..AgentConfig::default()
+        };
+        let yaml = config.to_yaml(AgentVersion::V3).unwrap();
+        assert_eq!(
+            AgentConfig::from_yaml(AgentVersion::V3, &yaml).unwrap(),
+            config
+        );
+        let mut http = spec;
+        http.proto = Proto::Http;
+        assert!(tunnel_request(AgentVersion::V3, &http).is_err());
+    }
+
+    #[test]
+    fn v3_tunnel_response() {
+        let mut v3_tunnel = tunnel_fixture(
+            "erp",
+            "https",
+            "https://abcd.ngrok-free.app",
+            "http://localhost:8069",
+        );
+        v3_tunnel["ID"] = json!("8f7d6e0a");
+        let tunnel = parse_tunnel(AgentVersion::V3, v3_tunnel).unwrap();
+        assert_eq!(tunnel.id.as_deref(), Some("8f7d6e0a"));
+        assert_eq!(tunnel.public_url, "https://abcd.ngrok-free.app");
+    }
+
+    #[test]
+    fn config_documents() {
+        let config = AgentConfig {
+            authtoken: None,
+            web_addr: Some("127.0.0.1:4041".to_owned()),
+            tunnels: vec![TunnelSpec::http("erp", 8069).bind_tls(BindTls::Https)],
+        };
+        assert_eq!(
+            config.to_value(AgentVersion::V2).unwrap(),
+            json!({"web_addr": "127.0.0.1:4041",
+                   "tunnels": {"erp": {"proto": "http", "addr": "8069", "bind_tls": true}}})
+        );
+        assert_eq!(
+            config.to_value(AgentVersion::V3).unwrap(),
+            json!({"version": "2", "web_addr": "127.0.0.1:4041",
+                   "tunnels": {"erp": {"proto": "http", "addr": "8069", "schemes": ["https"]}}})
+        );
+    }
+
+    #[test]
+    fn header_options_round_trip() {
+        let erp = TunnelSpec::http("erp", 8069)
+            .host_header(HostHeader::Rewrite)
+            .request_header(
+                HeaderRules::default()
+                    .add("X-Forwarded-Proto", "https")
+                    .unwrap(),
+            )
+            .response_header(HeaderRules::default().remove("Server").unwrap());
+        let config = AgentConfig {
+            tunnels: vec![erp.clone()],
+            ..AgentConfig::default()
+        };
+        let yaml = config.to_yaml(AgentVersion::V3).unwrap();
+        assert_eq!(
+            AgentConfig::from_yaml(AgentVersion::V3, &yaml).unwrap(),
+            config
+        );
+        assert!(config.to_value(AgentVersion::V2).is_err());
+
+        let v2 = AgentConfig {
+            tunnels: vec![TunnelSpec::http("erp", 8069)
+                .bind_tls(BindTls::Both)
+                .host_header(HostHeader::Rewrite)],
+            ..AgentConfig::default()
+        };
+        let yaml = v2.to_yaml(AgentVersion::V2).unwrap();
+        assert!(yaml.contains("host_header: rewrite"));
+        assert_eq!(AgentConfig::from_yaml(AgentVersion::V2, &yaml).unwrap(), v2);
+        let parsed = AgentConfig::from_yaml(
+            AgentVersion::V2,
+            "tunnels:\n  erp:\n    proto: http\n    addr: 8069\n    auth: \"alice:s3cret-enough\"\n",
+        )
+        .unwrap();
+        assert_eq!(parsed.tunnels[0].addr, Addr::Port(8069));
+        assert_eq!(parsed.tunnels[0].basic_auth[0].user(), "alice");
+    }
+
+    #[test]
+    fn header_options_checked_again() {
+        let injected = TunnelSpec::http("erp", 8069)
+            .host_header(HostHeader::Custom("erp\r\nX-Evil: 1".to_owned()));
+        let mut rules = HeaderRules::default();
+        rules.add.push(Header {
+            name: "X-Id".to_owned(),
+            value: "1\r\nX-Evil: 1".to_owned(),
+        });
+        let smuggled = TunnelSpec::http("erp", 8069).response_header(rules);
+        for spec in &[injected, smuggled] {
+            for version in &[AgentVersion::V2, AgentVersion::V3] {
+                let err = tunnel_request(*version, spec).unwrap_err();
+                assert!(matches!(err.kind(), ErrorKind::InvalidSpec(_)), "{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn ip_restrictions_checked_again() {
+        let host_bits = IpRestriction {
+            allow: vec!["10.1.2.3/8".parse().unwrap()],
+            deny: Vec::new(),
+        };
+        let empty = IpRestriction {
+            allow: Vec::new(),
+            deny: Vec::new(),
+        };
+        for restriction in [host_bits, empty] {
+            let mut spec = TunnelSpec::http("erp", 8069);
+            spec.ip_restriction = Some(restriction);
+            let err = tunnel_request(AgentVersion::V3, &spec).unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::InvalidSpec(_)), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Models of the ngrok 2.x agent API.
+
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+use crate::{
+    Addr, BindTls, Credentials, Error, ErrorKind, HeaderRules, HostHeader, Metrics, Proto, Tunnel,
+    TunnelConfig, TunnelSpec,
+};
+
+impl Serialize for BindTls {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            BindTls::Both => serializer.serialize_str("both"),
+            BindTls::Https => serializer.serialize_bool(true),
+            BindTls::Http => serializer.serialize_bool(false),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BindTls {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Value::deserialize(deserializer)? {
+            Value::Bool(true) => Ok(BindTls::Https),
+            Value::Bool(false) => Ok(BindTls::Http),
+            Value::String(ref both) if both == "both" => Ok(BindTls::Both),
+            other => Err(de::Error::custom(format!("bad bind_tls {}", other))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TunnelRequest {
+    #[serde(default)]
+    pub name: String,
+    pub proto: Proto,
+    pub addr: Addr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_tls: Option<BindTls>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inspect: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<Credentials>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_header: Option<HostHeader>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crt: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cas: Option<PathBuf>,
+}
+
+impl TryFrom<&TunnelSpec> for TunnelRequest {
+    type Error = Error;
+
+    fn try_from(spec: &TunnelSpec) -> Result<Self, Error> {
+        let unsupported = |what: &str| {
+            Error::from(ErrorKind::InvalidSpec(format!(
+                "tunnel {}: {} needs a v3 agent",
+                spec.name, what
+            )))
+        };
+        if spec.basic_auth.len() > 1 {
+            return Err(unsupported("more than one basic auth user"));
+        }
+        if spec.oauth.is_some() {
+            return Err(unsupported("OAuth"));
+        }
+        if spec.oidc.is_some() {
+            return Err(unsupported("OIDC"));
+        }
+        if !spec.request_header.is_empty() || !spec.response_header.is_empty() {
+            return Err(unsupported("header add/remove"));
+        }
+        if spec.ip_restriction.is_some() {
+            return Err(unsupported("IP restriction"));
+        }
+        if spec.traffic_policy.is_some() {
+            return Err(unsupported("traffic policy"));
+        }
+        Ok(TunnelRequest {
+            name: spec.name.clone(),
+            proto: spec.proto,
+            addr: spec.addr.clone(),
+            bind_tls: spec.bind_tls,
+            inspect: spec.inspect,
+            auth: spec.basic_auth.first().cloned(),
+            host_header: spec.host_header.clone(),
+            remote_addr: spec.remote_addr.clone(),
+            crt: spec.tls_termination.as_ref().map(|tls| tls.crt.clone()),
+            key: spec.tls_termination.as_ref().map(|tls| tls.key.clone()),
+            client_cas: spec
+                .tls_termination
+                .as_ref()
+                .and_then(|tls| tls.client_cas.clone()),
+        })
+    }
+}
+
+impl From<TunnelRequest> for TunnelSpec {
+    fn from(request: TunnelRequest) -> Self {
+        TunnelSpec {
+            name: request.name,
+            proto: request.proto,
+            addr: request.addr,
+            bind_tls: request.bind_tls,
+            inspect: request.inspect,
+            basic_auth: request.auth.into_iter().collect(),
+            oauth: None,
+            oidc: None,
+            host_header: request.host_header,
+            request_header: HeaderRules::default(),
+            response_header: HeaderRules::default(),
+            ip_restriction: None,
+            traffic_policy: None,
+            tls_termination: super::tls_termination(request.crt, request.key, request.client_cas),
+            remote_addr: request.remote_addr,
+            upstream_check: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TunnelResponse {
+    pub name: String,
+    pub uri: String,
+    pub public_url: String,
+    pub proto: String,
+    pub config: TunnelConfig,
+    pub metrics: Metrics,
+}
+
+impl From<TunnelResponse> for Tunnel {
+    fn from(tunnel: TunnelResponse) -> Self {
+        Tunnel {
+            id: None,
+            name: tunnel.name,
+            uri: tunnel.uri,
+            public_url: tunnel.public_url,
+            proto: tunnel.proto,
+            config: tunnel.config,
+            metrics: tunnel.metrics,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authtoken: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub web_addr: Option<String>,
+    #[serde(default)]
+    pub tunnels: BTreeMap<String, Value>,
+}
@@ -0,0 +1,161 @@
+//! Models of the ngrok 3.x agent API.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::{
+    Addr, BindTls, Credentials, HeaderRules, HostHeader, IpRestriction, Metrics, OAuth, Oidc,
+    Proto, TrafficPolicy, Tunnel, TunnelConfig, TunnelSpec,
+};
+
+/// Config file schema version expected by 3.x agents.
+pub const CONFIG_VERSION: &str = "2";
+
+fn schemes(bind_tls: BindTls) -> Vec<String> {
+    let schemes: &[&str] = match bind_tls {
+        BindTls::Both => &["https", "http"],
+        BindTls::Https => &["https"],
+        BindTls::Http => &["http"],
+    };
+    schemes.iter().map(|s| s.to_string()).collect()
+}
+
+fn bind_tls(schemes: &[String]) -> Option<BindTls> {
+    let https = schemes.iter().any(|s| s == "https");
+    let http = schemes.iter().any(|s| s == "http");
+    match (https, http) {
+        (true, true) => Some(BindTls::Both),
+        (true, false) => Some(BindTls::Https),
+        (false, true) => Some(BindTls::Http),
+        (false, false) => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TunnelRequest {
+    #[serde(default)]
+    pub name: String,
+    pub proto: Proto,
+    pub addr: Addr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schemes: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inspect: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub basic_auth: Vec<Credentials>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oauth: Option<OAuth>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oidc: Option<Oidc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_header: Option<HostHeader>,
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub request_header: HeaderRules,
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub response_header: HeaderRules,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_restriction: Option<IpRestriction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic_policy: Option<TrafficPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crt: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mutual_tls_cas: Option<PathBuf>,
+}
+
+impl From<&TunnelSpec> for TunnelRequest {
+    fn from(spec: &TunnelSpec) -> Self {
+        TunnelRequest {
+            name: spec.name.clone(),
+            proto: spec.proto,
+            addr: spec.addr.clone(),
+            schemes: spec.bind_tls.map(schemes),
+            inspect: spec.inspect,
+            basic_auth: spec.basic_auth.clone(),
+            oauth: spec.oauth.clone(),
+            oidc: spec.oidc.clone(),
+            host_header: spec.host_header.clone(),
+            request_header: spec.request_header.clone(),
+            response_header: spec.response_header.clone(),
+            ip_restriction: spec.ip_restriction.clone(),
+            traffic_policy: spec.traffic_policy.clone(),
+            remote_addr: spec.remote_addr.clone(),
+            crt: spec.tls_termination.as_ref().map(|tls| tls.crt.clone()),
+            key: spec.tls_termination.as_ref().map(|tls| tls.key.clone()),
+            mutual_tls_cas: spec
+                .tls_termination
+                .as_ref()
+                .and_then(|tls| tls.client_cas.clone()),
+        }
+    }
+}
+
+impl From<TunnelRequest> for TunnelSpec {
+    fn from(request: TunnelRequest) -> Self {
+        TunnelSpec {
+            name: request.name,
+            proto: request.proto,
+            addr: request.addr,
+            bind_tls: request.schemes.as_deref().and_then(bind_tls),
+            inspect: request.inspect,
+            basic_auth: request.basic_auth,
+            oauth: request.oauth,
+            oidc: request.oidc,
+            host_header: request.host_header,
+            request_header: request.request_header,
+            response_header: request.response_header,
+            ip_restriction: request.ip_restriction,
+            traffic_policy: request.traffic_policy,
+            tls_termination: super::tls_termination(
+                request.crt,
+                request.key,
+                request.mutual_tls_cas,
+            ),
+            remote_addr: request.remote_addr,
+            upstream_check: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TunnelResponse {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub name: String,
+    pub uri: String,
+    pub public_url: String,
+    pub proto: String,
+    pub config: TunnelConfig,
+    pub metrics: Metrics,
+}
+
+impl From<TunnelResponse> for Tunnel {
+    fn from(tunnel: TunnelResponse) -> Self {
+        Tunnel {
+            id: Some(tunnel.id),
+            name: tunnel.name,
+            uri: tunnel.uri,
+            public_url: tunnel.public_url,
+            proto: tunnel.proto,
+            config: tunnel.config,
+            metrics: tunnel.metrics,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authtoken: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub web_addr: Option<String>,
+    #[serde(default)]
+    pub tunnels: BTreeMap<String, Value>,
+}
@@ -0,0 +1,290 @@
+//! Authentication in front of a tunnel.
+//!
+//! Everything is validated when built, so a bad option is reported before
+//! anything reaches the agent. Secrets are serialized for the agent but
+//! never shown by `Debug`.
+
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use url::Url;
+
+use crate::{ErrorKind, Result};
+
+/// Providers the ngrok edge supports for OAuth.
+const OAUTH_PROVIDERS: &[&str] = &[
+    "amazon",
+    "facebook",
+    "github",
+    "gitlab",
+    "google",
+    "linkedin",
+    "microsoft",
+    "twitch",
+];
+
+fn invalid<T>(reason: String) -> Result<T> {
+    Err(ErrorKind::InvalidSpec(reason).into())
+}
+
+/// One basic auth user.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    user: String,
+    password: String,
+}
+
+impl Credentials {
+    /// ngrok wants a non-empty user without `:` and a password of 8 to 128
+    /// characters.
+    pub fn new<U, P>(user: U, password: P) -> Result<Self>
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        let user = user.into();
+        let password = password.into();
+        if user.is_empty() || user.contains(':') {
+            return invalid(format!("basic auth user {:?} is empty or has a ':'", user));
+        }
+        if !(8..=128).contains(&password.chars().count()) {
+            return invalid(format!(
+                "basic auth password of {} must be 8 to 128 characters long",
+                user
+            ));
+        }
+        Ok(Credentials { user, password })
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+/// Sent as `user:password`.
+impl Serialize for Credentials {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{}:{}", self.user, self.password))
+    }
+}
+
+impl<'de> Deserialize<'de> for Credentials {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
+        let (user, password) = text
+            .split_once(':')
+            .ok_or_else(|| de::Error::custom("basic auth is not `user:password`"))?;
+        Credentials::new(user, password).map_err(de::Error::custom)
+    }
+}
+
+fn check_emails(emails: &[String]) -> Result<()> {
+    match emails.iter().find(|email| !email.contains('@')) {
+        Some(email) => invalid(format!("{:?} is not an email address", email)),
+        None => Ok(()),
+    }
+}
+
+fn check_domains(domains: &[String]) -> Result<()> {
+    match domains
+        .iter()
+        .find(|domain| domain.is_empty() || domain.contains('@') || domain.contains('/'))
+    {
+        Some(domain) => invalid(format!("{:?} is not a domain", domain)),
+        None => Ok(()),
+    }
+}
+
+/// Log in with an OAuth provider (v3 agents only).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OAuth {
+    provider: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allow_emails: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allow_domains: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    scopes: Vec<String>,
+}
+
+impl OAuth {
+    pub fn new<P: Into<String>>(provider: P) -> Result<Self> {
+        let provider = provider.into();
+        if !OAUTH_PROVIDERS.contains(&provider.as_str()) {
+            return invalid(format!("unknown OAuth provider {:?}", provider));
+        }
+        Ok(OAuth {
+            provider,
+            allow_emails: Vec::new(),
+            allow_domains: Vec::new(),
+            scopes: Vec::new(),
+        })
+    }
+
+    /// Only let these users in.
+    pub fn allow_emails<I, S>(mut self, emails: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allow_emails.extend(emails.into_iter().map(Into::into));
+        check_emails(&self.allow_emails)?;
+        Ok(self)
+    }
+
+    /// Only let users of these email domains in.
+    pub fn allow_domains<I, S>(mut self, domains: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allow_domains
+            .extend(domains.into_iter().map(Into::into));
+        check_domains(&self.allow_domains)?;
+        Ok(self)
+    }
+
+    pub fn scopes<I, S>(mut self, scopes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.scopes.extend(scopes.into_iter().map(Into::into));
+        self
+    }
+}
+
+/// Log in with any OpenID Connect identity provider (v3 agents only).
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Oidc {
+    issuer_url: String,
+    client_id: String,
+    client_secret: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    scopes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allow_emails: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allow_domains: Vec<String>,
+}
+
+impl Oidc {
+    pub fn new<I, C, S>(issuer_url: I, client_id: C, client_secret: S) -> Result<Self>
+    where
+        I: AsRef<str>,
+        C: Into<String>,
+        S: Into<String>,
+    {
+        let issuer = match Url::parse(issuer_url.as_ref()) {
+            Ok(url) if url.scheme() == "https" => url,
+            _ => {
+                return invalid(format!(
+                    "OIDC issuer {:?} is not an https URL",
+                    issuer_url.as_ref()
+                ))
+            }
+        };
+        let client_id = client_id.into();
+        let client_secret = client_secret.into();
+        if client_id.is_empty() || client_secret.is_empty() {
+            return invalid("OIDC client id and secret are required".to_owned());
+        }
+        Ok(Oidc {
+            issuer_url: issuer.to_string(),
+            client_id,
+            client_secret,
+            scopes: Vec::new(),
+            allow_emails: Vec::new(),
+            allow_domains: Vec::new(),
+        })
+    }
+
+    pub fn allow_emails<I, S>(mut self, emails: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allow_emails.extend(emails.into_iter().map(Into::into));
+        check_emails(&self.allow_emails)?;
+        Ok(self)
+    }
+
+    pub fn allow_domains<I, S>(mut self, domains: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allow_domains
+            .extend(domains.into_iter().map(Into::into));
+        check_domains(&self.allow_domains)?;
+        Ok(self)
+    }
+
+    pub fn scopes<I, S>(mut self, scopes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.scopes.extend(scopes.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl fmt::Debug for Oidc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Oidc")
+            .field("issuer_url", &self.issuer_url)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"***")
+            .field("scopes", &self.scopes)
+            .field("allow_emails", &self.allow_emails)
+            .field("allow_domains", &self.allow_domains)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Credentials, OAuth, Oidc};
+    use crate::ErrorKind;
+
+    #[test]
+    fn validation() {
+        assert!(Credentials::new("alice", "s3cret-enough").is_ok());
+        let err = Credentials::new("alice", "short").unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidSpec(_)));
+        assert!(Credentials::new("al:ice", "s3cret-enough").is_err());
+        assert!(OAuth::new("myspace").is_err());
+        assert!(OAuth::new("google").unwrap().allow_emails(["bob"]).is_err());
+        assert!(Oidc::new("http://id.example.com", "client", "secret").is_err());
+    }
+
+    #[test]
+    fn secrets_are_not_shown() {
+        let credentials = Credentials::new("alice", "s3cret-enough").unwrap();
+        let oidc = Oidc::new("https://id.example.com", "client", "oidc-secret").unwrap();
+        let shown = format!("{:?} {:?}", credentials, oidc);
+        assert!(shown.contains("alice") && shown.contains("client"));
+        assert!(!shown.contains("s3cret-enough") && !shown.contains("oidc-secret"));
+        assert_eq!(
+            serde_json::to_value(&credentials).unwrap(),
+            "alice:s3cret-enough"
+        );
+    }
+}
@@ -0,0 +1,395 @@
+//! Requests captured by the agent's inspector, for test assertions.
+//!
+//! `Ngrok::wait_for_request` polls `api/requests/http` until a request on
+//! the tunnel passes a predicate: a closure or a `RequestMatch`. Only
+//! requests arriving after the call count, so a test can wait for the
+//! webhook it just triggered even when earlier ones are still listed. Each
+//! poll asks for more until it reaches the last request already seen, so a
+//! burst of traffic between two polls is not missed.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::debug;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{ErrorKind, Ngrok, Result, Tunnel};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Requests asked for at once; the agent keeps the last 50 by default.
+const LIMIT: usize = 50;
+
+#[derive(Debug, Clone, Deserialize)]
+struct RawRequest {
+    method: String,
+    uri: String,
+    #[serde(default)]
+    headers: BTreeMap<String, Vec<String>>,
+    /// The whole request as received, base64 encoded.
+    #[serde(default)]
+    raw: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RawCapture {
+    id: String,
+    tunnel_name: String,
+    remote_addr: String,
+    #[serde(default)]
+    start: String,
+    request: RawRequest,
+}
+
+#[derive(Debug, Deserialize)]
+struct Captures {
+    #[serde(default)]
+    requests: Vec<RawCapture>,
+}
+
+/// An HTTP request seen by the agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedRequest {
+    pub id: String,
+    pub tunnel_name: String,
+    pub remote_addr: String,
+    /// When it arrived, as the agent reports it (RFC 3339).
+    pub start: String,
+    pub method: String,
+    /// Path and query, e.g. `/hooks/github?delivery=1`.
+    pub uri: String,
+    pub headers: BTreeMap<String, Vec<String>>,
+    pub body: Vec<u8>,
+}
+
+impl From<RawCapture> for CapturedRequest {
+    fn from(capture: RawCapture) -> Self {
+        let raw = STANDARD
+            .decode(capture.request.raw.as_bytes())
+            .unwrap_or_default();
+        let body = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => raw[end + 4..].to_vec(),
+            None => Vec::new(),
+        };
+        CapturedRequest {
+            id: capture.id,
+            tunnel_name: capture.tunnel_name,
+            remote_addr: capture.remote_addr,
+            start: capture.start,
+            method: capture.request.method,
+            uri: capture.request.uri,
+            headers: capture.request.headers,
+            body,
+        }
+    }
+}
+
+impl CapturedRequest {
+    /// `uri` without the query.
+    pub fn path(&self) -> &str {
+        self.uri.split('?').next().unwrap_or("")
+    }
+
+    /// First value of the header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, values)| values.first())
+            .map(String::as_str)
+    }
+
+    pub fn body_text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    /// One line for error reports.
+    fn summary(&self) -> String {
+        format!("{} {} from {}", self.method, self.uri, self.remote_addr)
+    }
+}
+
+/// What `Ngrok::wait_for_request` waits for.
+pub trait RequestPredicate {
+    fn accepts(&self, request: &CapturedRequest) -> bool;
+}
+
+impl<F> RequestPredicate for F
+where
+    F: Fn(&CapturedRequest) -> bool,
+{
+    fn accepts(&self, request: &CapturedRequest) -> bool {
+        self(request)
+    }
+}
+
+/// A request predicate built from parts; unset parts match anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestMatch {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body_contains: Option<String>,
+}
+
+impl RequestMatch {
+    pub fn method<M: Into<String>>(mut self, method: M) -> Self {
+        self.method = Some(method.into());
+        self
+    }
+
+    pub fn path<P: Into<String>>(mut self, path: P) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// The header `name` must have exactly `value`.
+    pub fn header<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body_contains<T: Into<String>>(mut self, text: T) -> Self {
+        self.body_contains = Some(text.into());
+        self
+    }
+}
+
+impl RequestPredicate for RequestMatch {
+    fn accepts(&self, request: &CapturedRequest) -> bool {
+        let method_ok = self
+            .method
+            .as_ref()
+            .is_none_or(|method| method.eq_ignore_ascii_case(&request.method));
+        let path_ok = self.path.as_ref().is_none_or(|path| path == request.path());
+        let headers_ok = self
+            .headers
+            .iter()
+            .all(|(name, value)| request.header(name) == Some(value.as_str()));
+        let body_ok = self
+            .body_contains
+            .as_ref()
+            .is_none_or(|text| request.body_text().contains(text.as_str()));
+        method_ok && path_ok && headers_ok && body_ok
+    }
+}
+
+/// The tunnel itself and the http companion of a `BindTls::Both` tunnel.
+fn capture_names(tunnel: &Tunnel) -> [String; 2] {
+    [tunnel.name.clone(), format!("{} (http)", tunnel.name)]
+}
+
+/// Newest first, by the arrival time the agent reports.
+fn newest_first(requests: &mut [CapturedRequest]) {
+    requests.sort_by(|a, b| b.start.cmp(&a.start));
+}
+
+impl Ngrok {
+    /// Up to `limit` requests captured on the tunnel `name`, newest first.
+    fn requests_on(&self, name: &str, limit: usize) -> Result<Vec<CapturedRequest>> {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("limit", &limit.to_string())
+            .append_pair("tunnel_name", name)
+            .finish();
+        let captures = self.get::<Captures>(&format!("api/requests/http?{}", query))?;
+        Ok(captures
+            .requests
+            .into_iter()
+            .map(CapturedRequest::from)
+            .collect())
+    }
+
+    /// Requests captured on the tunnel `name` after the one with the id
+    /// `last_seen`, newest first; all of them without one. Asks for more
+    /// until `last_seen` is listed or the agent has nothing older.
+    fn requests_since(&self, name: &str, last_seen: Option<&str>) -> Result<Vec<CapturedRequest>> {
+        let mut limit = LIMIT;
+        loop {
+            let mut requests = self.requests_on(name, limit)?;
+            if let Some(seen) = requests
+                .iter()
+                .position(|r| Some(r.id.as_str()) == last_seen)
+            {
+                requests.truncate(seen);
+                return Ok(requests);
+            }
+            if requests.len() < limit {
+                if last_seen.is_some() {
+                    debug!("request {:?} no longer listed on {}", last_seen, name);
+                }
+                return Ok(requests);
+            }
+            limit *= 2;
+        }
+    }
+
+    /// Requests captured on `tunnel`, newest first. Those arriving on the
+    /// http companion of a `BindTls::Both` tunnel are included.
+    pub fn captured_requests(&self, tunnel: &Tunnel) -> Result<Vec<CapturedRequest>> {
+        let mut requests = Vec::new();
+        for name in &capture_names(tunnel) {
+            requests.extend(self.requests_on(name, LIMIT)?);
+        }
+        newest_first(&mut requests);
+        Ok(requests)
+    }
+
+    /// Wait until a request passing `predicate` arrives on `tunnel`, and
+    /// return it. Requests captured before the call are ignored.
+    pub fn wait_for_request<P>(
+        &self,
+        tunnel: &Tunnel,
+        predicate: P,
+        timeout: Duration,
+    ) -> Result<CapturedRequest>
+    where
+        P: RequestPredicate,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut last_seen = HashMap::new();
+        for name in capture_names(tunnel) {
+            let newest = self.requests_on(&name, 1)?.into_iter().next();
+            last_seen.insert(name, newest.map(|request| request.id));
+        }
+        let mut arrived = Vec::new();
+        loop {
+            thread::sleep(POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+            let mut new = Vec::new();
+            for (name, seen) in last_seen.iter_mut() {
+                let requests = self.requests_since(name, seen.as_deref())?;
+                if let Some(newest) = requests.first() {
+                    *seen = Some(newest.id.clone());
+                }
+                new.extend(requests);
+            }
+            newest_first(&mut new);
+            // oldest first, so the first match is the earliest one
+            for request in new.into_iter().rev() {
+                if predicate.accepts(&request) {
+                    return Ok(request);
+                }
+                debug!("ignoring request {}", request.summary());
+                arrived.push(request.summary());
+            }
+            if Instant::now() >= deadline {
+                return Err(ErrorKind::NoMatchingRequest(tunnel.name.clone(), arrived).into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestMatch;
+    use crate::transport::{Request, Response, Transport};
+    use crate::{tunnel_fixture, ErrorKind, Ngrok, Result, Tunnel};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// The `n`th request to arrive, in seconds after 10:00.
+    fn capture(n: usize, id: &str, method: &str, uri: &str, body: &str) -> Value {
+        let raw = format!(
+            "{} {} HTTP/1.1\r\nHost: abcd.ngrok.app\r\nX-Event: push\r\n\r\n{}",
+            method, uri, body
+        );
+        json!({
+            "id": id, "tunnel_name": "hooks", "remote_addr": "140.82.115.1",
+            "start": format!("2024-05-01T10:{:02}:{:02}Z", n / 60, n % 60),
+            "request": {
+                "method": method, "uri": uri, "proto": "HTTP/1.1",
+                "headers": {"X-Event": ["push"]},
+                "raw": STANDARD.encode(raw)
+            }
+        })
+    }
+
+    /// Lists an old request on `hooks`, then from the second poll on a new
+    /// one followed by `burst` health checks.
+    #[derive(Debug, Default)]
+    struct Inspector {
+        polls: AtomicUsize,
+        burst: usize,
+    }
+
+    impl Transport for Inspector {
+        fn send(&self, request: Request) -> Result<Response> {
+            let query: HashMap<_, _> = request.url.query_pairs().into_owned().collect();
+            let limit: usize = query["limit"].parse().unwrap();
+            let mut requests = Vec::new();
+            if query["tunnel_name"] == "hooks" {
+                requests.push(capture(0, "old", "POST", "/hook", "{\"ok\": true}"));
+                if self.polls.fetch_add(1, Ordering::SeqCst) > 0 {
+                    let new = capture(1, "new", "POST", "/hook?n=2", "{\"ok\": true}");
+                    requests.insert(0, new);
+                    for n in 0..self.burst {
+                        let id = format!("health-{}", n);
+                        requests.insert(0, capture(n + 2, &id, "GET", "/health", ""));
+                    }
+                }
+            }
+            requests.truncate(limit);
+            let body = json!({"uri": "/api/requests/http", "requests": requests});
+            Ok(Response::new(200, "OK", body.to_string()))
+        }
+    }
+
+    fn tunnel() -> Tunnel {
+        let hooks = "https://abcd.ngrok.app";
+        serde_json::from_value(tunnel_fixture(
+            "hooks",
+            "https",
+            hooks,
+            "http://localhost:8080",
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn waits_for_new_requests() {
+        let inspector = |burst| Inspector {
+            burst,
+            ..Inspector::default()
+        };
+        // more arrive between two polls than the agent lists at once
+        let ngrok = Ngrok::with_transport(inspector(60));
+        let hook = RequestMatch::default()
+            .method("post")
+            .path("/hook")
+            .header("x-event", "push")
+            .body_contains("\"ok\"");
+        let request = ngrok
+            .wait_for_request(&tunnel(), hook, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(request.id, "new");
+        assert_eq!(request.body_text(), "{\"ok\": true}");
+
+        let ngrok = Ngrok::with_transport(inspector(1));
+        let never = |request: &super::CapturedRequest| request.method == "DELETE";
+        let err = ngrok
+            .wait_for_request(&tunnel(), never, Duration::from_millis(300))
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::NoMatchingRequest(name, arrived) => {
+                assert_eq!(name, "hooks");
+                assert_eq!(
+                    arrived,
+                    &[
+                        "POST /hook?n=2 from 140.82.115.1",
+                        "GET /health from 140.82.115.1",
+                    ]
+                );
+            }
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+}
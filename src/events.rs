@@ -0,0 +1,228 @@
+//! Tunnel lifecycle events, obtained by diffing successive `api/tunnels`
+//! snapshots.
+
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+use crate::{Error, ErrorKind, Ngrok, Tunnel, Tunnels};
+
+#[derive(Debug, Clone)]
+pub enum TunnelEvent {
+    TunnelAdded(Tunnel),
+    TunnelRemoved(Tunnel),
+    PublicUrlChanged {
+        name: String,
+        old: String,
+        new: String,
+    },
+    /// The local API stopped answering. The last known tunnels are kept, so
+    /// tunnels recreated by a restarted agent show up as URL changes.
+    AgentDown,
+    AgentUp,
+    /// The local API answered, but not with a tunnel list, e.g. with a 500.
+    /// Reported once until a poll succeeds or fails differently.
+    Error(String),
+}
+
+/// Whether `err` means nothing answered, rather than a bad answer. Any
+/// transport reports a missing answer as an error of its own choosing, so
+/// this goes by what the error is not.
+fn is_unreachable(err: &Error) -> bool {
+    !matches!(
+        err.kind(),
+        ErrorKind::Server(..) | ErrorKind::MalformedResponse(..)
+    )
+}
+
+/// Events turning the `old` tunnel list into the `new` one.
+fn diff(old: &[Tunnel], new: &[Tunnel]) -> Vec<TunnelEvent> {
+    let mut events = Vec::new();
+    for tunnel in old {
+        if !new.iter().any(|t| t.name == tunnel.name) {
+            events.push(TunnelEvent::TunnelRemoved(tunnel.clone()));
+        }
+    }
+    for tunnel in new {
+        match old.iter().find(|t| t.name == tunnel.name) {
+            None => events.push(TunnelEvent::TunnelAdded(tunnel.clone())),
+            Some(previous) if previous.public_url != tunnel.public_url => {
+                events.push(TunnelEvent::PublicUrlChanged {
+                    name: tunnel.name.clone(),
+                    old: previous.public_url.clone(),
+                    new: tunnel.public_url.clone(),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    events
+}
+
+/// Endless iterator of tunnel events, see `Ngrok::watch_tunnels`.
+#[derive(Debug)]
+pub struct TunnelEvents {
+    ngrok: Ngrok,
+    interval: Duration,
+    agent_up: Option<bool>,
+    tunnels: Vec<Tunnel>,
+    pending: VecDeque<TunnelEvent>,
+    last_error: Option<String>,
+    first: bool,
+}
+
+impl TunnelEvents {
+    fn poll(&mut self) {
+        match self.ngrok.get::<Tunnels>("api/tunnels") {
+            Ok(tunnels) => {
+                if self.agent_up != Some(true) {
+                    self.pending.push_back(TunnelEvent::AgentUp);
+                    self.agent_up = Some(true);
+                }
+                self.pending.extend(diff(&self.tunnels, &tunnels.tunnels));
+                self.tunnels = tunnels.tunnels;
+                self.last_error = None;
+            }
+            Err(err) if !is_unreachable(&err) => {
+                let message = err.to_string();
+                if self.last_error.as_ref() != Some(&message) {
+                    warn!("could not list tunnels: {}", message);
+                    self.pending.push_back(TunnelEvent::Error(message.clone()));
+                    self.last_error = Some(message);
+                }
+            }
+            Err(err) => {
+                debug!("agent not answering: {}", err);
+                if self.agent_up != Some(false) {
+                    self.pending.push_back(TunnelEvent::AgentDown);
+                    self.agent_up = Some(false);
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for TunnelEvents {
+    type Item = TunnelEvent;
+
+    /// Blocks, polling every `interval`, until there is something to report.
+    fn next(&mut self) -> Option<TunnelEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            if self.first {
+                self.first = false;
+            } else {
+                thread::sleep(self.interval);
+            }
+            self.poll();
+        }
+    }
+}
+
+impl Ngrok {
+    /// Watch the agent's tunnels, polling every `interval`. The tunnels
+    /// already open are reported as `TunnelAdded` first.
+    pub fn watch_tunnels(&self, interval: Duration) -> TunnelEvents {
+        TunnelEvents {
+            ngrok: self.clone(),
+            interval,
+            agent_up: None,
+            tunnels: Vec::new(),
+            pending: VecDeque::new(),
+            last_error: None,
+            first: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, TunnelEvent};
+    use crate::transport::{Request, Response, Transport};
+    use crate::{tunnel_fixture, Ngrok, Result, Tunnel};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// An agent whose API fails.
+    #[derive(Debug)]
+    struct Failing;
+
+    impl Transport for Failing {
+        fn send(&self, _request: Request) -> Result<Response> {
+            Ok(Response::new(500, "Internal Server Error", "{}"))
+        }
+    }
+
+    /// An agent that does not answer the first poll, then has no tunnels.
+    #[derive(Debug, Default)]
+    struct Restarting {
+        polls: AtomicUsize,
+    }
+
+    impl Transport for Restarting {
+        fn send(&self, _request: Request) -> Result<Response> {
+            if self.polls.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err("connection refused".into());
+            }
+            Ok(Response::new(200, "OK", r#"{"tunnels": []}"#))
+        }
+    }
+
+    fn tunnel(name: &str, public_url: &str) -> Tunnel {
+        serde_json::from_value(tunnel_fixture(
+            name,
+            "https",
+            public_url,
+            "http://localhost:8069",
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let old = vec![
+            tunnel("erp", "https://a.ngrok.io"),
+            tunnel("ota", "https://b.ngrok.io"),
+        ];
+        let new = vec![
+            tunnel("erp", "https://c.ngrok.io"),
+            tunnel("api", "https://d.ngrok.io"),
+        ];
+        let events = diff(&old, &new);
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], TunnelEvent::TunnelRemoved(t) if t.name == "ota"));
+        assert!(
+            matches!(&events[1], TunnelEvent::PublicUrlChanged { name, old, new }
+            if name == "erp" && old == "https://a.ngrok.io" && new == "https://c.ngrok.io")
+        );
+        assert!(matches!(&events[2], TunnelEvent::TunnelAdded(t) if t.name == "api"));
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn agent_down_or_failing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let mut events = Ngrok::new()
+            .with_web_addr(addr)
+            .watch_tunnels(Duration::ZERO);
+        assert!(matches!(events.next(), Some(TunnelEvent::AgentDown)));
+
+        let mut events = Ngrok::with_transport(Restarting::default()).watch_tunnels(Duration::ZERO);
+        assert!(matches!(events.next(), Some(TunnelEvent::AgentDown)));
+        assert!(matches!(events.next(), Some(TunnelEvent::AgentUp)));
+
+        let mut events = Ngrok::with_transport(Failing).watch_tunnels(Duration::ZERO);
+        assert!(
+            matches!(events.next(), Some(TunnelEvent::Error(message)) if message.contains("500"))
+        );
+        assert!(events.pending.is_empty());
+        events.poll();
+        assert!(events.pending.is_empty());
+    }
+}
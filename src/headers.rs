@@ -0,0 +1,236 @@
+//! Header rewriting on http tunnels.
+
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::{Error, ErrorKind, Result};
+
+/// The `Host` header the upstream receives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostHeader {
+    /// The public hostname, as the client sent it.
+    Preserve,
+    /// The hostname of the upstream address, e.g. `localhost:8069`.
+    Rewrite,
+    /// Build with `HostHeader::custom`, which checks the value.
+    Custom(String),
+}
+
+/// A custom `Host` value cannot be empty, contain whitespace, or be one of
+/// the mode names, which the agent would read as that mode.
+fn check_host(host: &str) -> Result<()> {
+    let reserved = ["preserve", "rewrite"]
+        .iter()
+        .any(|mode| host.eq_ignore_ascii_case(mode));
+    if host.is_empty() || reserved || host.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(ErrorKind::InvalidSpec(format!("{:?} is not a host header", host)).into());
+    }
+    Ok(())
+}
+
+impl HostHeader {
+    /// A fixed `Host` value, see `check`.
+    pub fn custom<H: Into<String>>(host: H) -> Result<Self> {
+        let host = host.into();
+        check_host(&host)?;
+        Ok(HostHeader::Custom(host))
+    }
+
+    /// Check a `Custom` value again, e.g. one built without `custom` right
+    /// before requesting the tunnel.
+    pub fn check(&self) -> Result<()> {
+        match self {
+            HostHeader::Custom(host) => check_host(host),
+            _ => Ok(()),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            HostHeader::Preserve => "preserve",
+            HostHeader::Rewrite => "rewrite",
+            HostHeader::Custom(host) => host,
+        }
+    }
+}
+
+impl FromStr for HostHeader {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "preserve" => Ok(HostHeader::Preserve),
+            "rewrite" => Ok(HostHeader::Rewrite),
+            host => HostHeader::custom(host),
+        }
+    }
+}
+
+impl Serialize for HostHeader {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for HostHeader {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if name.is_empty() || !name.chars().all(token) {
+        return Err(ErrorKind::InvalidSpec(format!("{:?} is not a header name", name)).into());
+    }
+    Ok(())
+}
+
+/// A line break in a value would start a header of its own.
+fn check_value(value: &str) -> Result<()> {
+    if value.contains(['\r', '\n', '\0']) {
+        return Err(ErrorKind::InvalidSpec(format!("{:?} is not a header value", value)).into());
+    }
+    Ok(())
+}
+
+/// A header to add, sent as `Name: value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+impl Serialize for Header {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{}: {}", self.name, self.value))
+    }
+}
+
+impl<'de> Deserialize<'de> for Header {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
+        let (name, value) = text
+            .split_once(':')
+            .ok_or_else(|| de::Error::custom(format!("{:?} is not `Name: value`", text)))?;
+        check_name(name.trim()).map_err(de::Error::custom)?;
+        check_value(value).map_err(de::Error::custom)?;
+        Ok(Header {
+            name: name.trim().to_owned(),
+            value: value.trim().to_owned(),
+        })
+    }
+}
+
+/// Headers added to and removed from requests or responses.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderRules {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add: Vec<Header>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+
+    pub fn add<N, V>(mut self, name: N, value: V) -> Result<Self>
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        let name = name.into();
+        let value = value.into();
+        check_name(&name)?;
+        check_value(&value)?;
+        self.add.push(Header { name, value });
+        Ok(self)
+    }
+
+    pub fn remove<N: Into<String>>(mut self, name: N) -> Result<Self> {
+        let name = name.into();
+        check_name(&name)?;
+        self.remove.push(name);
+        Ok(self)
+    }
+
+    /// Check every rule again, e.g. ones pushed without `add` or `remove`
+    /// right before requesting the tunnel.
+    pub fn check(&self) -> Result<()> {
+        for header in &self.add {
+            check_name(&header.name)?;
+            check_value(&header.value)?;
+        }
+        self.remove.iter().try_for_each(|name| check_name(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HeaderRules, HostHeader};
+    use crate::ErrorKind;
+    use serde_json::json;
+
+    #[test]
+    fn header_wire_format() {
+        let rules = HeaderRules::default()
+            .add("X-Forwarded-Proto", "https")
+            .unwrap()
+            .remove("Server")
+            .unwrap();
+        let value = serde_json::to_value(&rules).unwrap();
+        assert_eq!(
+            value,
+            json!({"add": ["X-Forwarded-Proto: https"], "remove": ["Server"]})
+        );
+        assert_eq!(serde_json::from_value::<HeaderRules>(value).unwrap(), rules);
+        assert!(HeaderRules::default().add("Bad Name", "x").is_err());
+        assert!(HeaderRules::default()
+            .add("X-Id", "1\r\nSet-Cookie: a=b")
+            .is_err());
+        assert!(serde_json::from_value::<HeaderRules>(json!({"add": ["X-Id: 1\n"]})).is_err());
+    }
+
+    #[test]
+    fn host_header_values() {
+        assert_eq!(
+            serde_json::from_value::<HostHeader>(json!("erp.example.com")).unwrap(),
+            HostHeader::custom("erp.example.com").unwrap()
+        );
+        assert_eq!(
+            serde_json::from_value::<HostHeader>(json!("rewrite")).unwrap(),
+            HostHeader::Rewrite
+        );
+        for bad in &[
+            "",
+            "Rewrite",
+            "preserve",
+            "erp.example.com\r\nX-Id: 1",
+            "a b",
+        ] {
+            match HostHeader::custom(*bad).unwrap_err().kind() {
+                ErrorKind::InvalidSpec(_) => {}
+                kind => panic!("unexpected error: {:?}", kind),
+            }
+        }
+        assert!(serde_json::from_value::<HostHeader>(json!("")).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Restricting tunnels to some client addresses.
+
+use ipnet::IpNet;
+use serde::de::{self, Deserializer};
+use serde::ser::{SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{ErrorKind, Result};
+
+fn serialize_nets<S>(nets: &[IpNet], serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut seq = serializer.serialize_seq(Some(nets.len()))?;
+    for net in nets {
+        seq.serialize_element(&net.to_string())?;
+    }
+    seq.end()
+}
+
+fn deserialize_nets<'de, D>(deserializer: D) -> std::result::Result<Vec<IpNet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let texts = Vec::<String>::deserialize(deserializer)?;
+    texts
+        .iter()
+        .map(|text| parse_net(text).map_err(de::Error::custom))
+        .collect()
+}
+
+/// Parse `10.0.0.0/8`; a bare address is a single host.
+fn parse_net(text: &str) -> Result<IpNet> {
+    let net = match text.parse::<IpNet>() {
+        Ok(net) => net,
+        Err(_) => match text.parse::<IpAddr>() {
+            Ok(addr) => IpNet::from(addr),
+            Err(_) => {
+                return Err(ErrorKind::InvalidSpec(format!("{:?} is not a CIDR", text)).into())
+            }
+        },
+    };
+    check_net(&net)?;
+    Ok(net)
+}
+
+/// ngrok refuses ranges with host bits set, like `10.1.2.3/8`.
+fn check_net(net: &IpNet) -> Result<()> {
+    if net.addr() != net.network() {
+        return Err(ErrorKind::InvalidSpec(format!(
+            "{} has host bits set, did you mean {}?",
+            net,
+            net.trunc()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Client addresses allowed to use a tunnel (v3 agents only). Denied
+/// ranges win over allowed ones; with no allowed range, all addresses not
+/// denied are allowed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpRestriction {
+    #[serde(
+        default,
+        rename = "allow_cidrs",
+        serialize_with = "serialize_nets",
+        deserialize_with = "deserialize_nets",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub allow: Vec<IpNet>,
+    #[serde(
+        default,
+        rename = "deny_cidrs",
+        serialize_with = "serialize_nets",
+        deserialize_with = "deserialize_nets",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub deny: Vec<IpNet>,
+}
+
+impl IpRestriction {
+    pub fn new<A, D>(allow: A, deny: D) -> Result<Self>
+    where
+        A: IntoIterator<Item = IpNet>,
+        D: IntoIterator<Item = IpNet>,
+    {
+        let restriction = IpRestriction {
+            allow: allow.into_iter().collect(),
+            deny: deny.into_iter().collect(),
+        };
+        restriction.check()?;
+        Ok(restriction)
+    }
+
+    /// Check the ranges again, e.g. of a restriction built without `new`
+    /// right before requesting the tunnel.
+    pub fn check(&self) -> Result<()> {
+        for net in self.allow.iter().chain(&self.deny) {
+            check_net(net)?;
+        }
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return Err(ErrorKind::InvalidSpec("empty IP restriction".to_owned()).into());
+        }
+        Ok(())
+    }
+
+    /// Build from CIDR strings, e.g. from a config file or the environment.
+    pub fn parse(allow: &[&str], deny: &[&str]) -> Result<Self> {
+        let allow = allow
+            .iter()
+            .map(|text| parse_net(text))
+            .collect::<Result<Vec<_>>>()?;
+        let deny = deny
+            .iter()
+            .map(|text| parse_net(text))
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(allow, deny)
+    }
+
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr))
+    }
+
+    /// Whether a request from `remote_addr`, as the agent reports it (with
+    /// or without a port), would have been let through.
+    pub fn allows_remote(&self, remote_addr: &str) -> Result<bool> {
+        let addr = match remote_addr.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip(),
+            Err(_) => remote_addr
+                .trim_matches(|c| c == '[' || c == ']')
+                .parse::<IpAddr>()
+                .map_err(|_| format!("bad remote address {:?}", remote_addr))?,
+        };
+        Ok(self.allows(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpRestriction;
+    use crate::ErrorKind;
+    use ipnet::IpNet;
+
+    #[test]
+    fn allow_and_deny() {
+        let office: IpNet = "203.0.113.0/24".parse().unwrap();
+        let printer: IpNet = "203.0.113.9/32".parse().unwrap();
+        let restriction = IpRestriction::new(vec![office], vec![printer]).unwrap();
+        assert!(restriction.allows_remote("203.0.113.7:51234").unwrap());
+        assert!(!restriction.allows_remote("203.0.113.9").unwrap());
+        assert!(!restriction.allows_remote("198.51.100.1").unwrap());
+        assert!(restriction.allows_remote("nowhere").is_err());
+
+        let err = IpRestriction::parse(&["10.1.2.3/8"], &[]).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidSpec(_)));
+        assert!(IpRestriction::parse(&["10.0.0.0/33"], &[]).is_err());
+        assert!(IpRestriction::parse(&[], &[]).is_err());
+        let ci = IpRestriction::parse(&["2001:db8::/32", "192.0.2.10"], &[]).unwrap();
+        assert_eq!(
+            serde_json::to_value(&ci).unwrap(),
+            serde_json::json!({"allow_cidrs": ["2001:db8::/32", "192.0.2.10/32"]})
+        );
+    }
+}
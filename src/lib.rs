@@ -1,39 +1,163 @@
 use log::{debug, error, info};
 use serde_json::Value;
-use std::cfg;
+use std::collections::HashMap;
 use std::env;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
 use std::io::BufReader;
 use std::io::BufWriter;
+use std::net::{SocketAddr, ToSocketAddrs};
 #[cfg(target_os = "linux")]
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::{fs, io::prelude::*, str};
-use std::{fs::File, io::Read};
-use std::{thread, time};
-use ureq;
 use url::Url;
 
-use unzip;
 #[macro_use]
 extern crate error_chain;
-error_chain! {
-    types {
-        Error, ErrorKind, ResultExt, Result;
-    }
+/// In a module of its own so the cfg error_chain checks for, unknown to
+/// this crate, can be allowed without touching the rest of it.
+#[allow(unexpected_cfgs)]
+mod errors {
+    use crate::{SetupStep, StartupError};
 
-    errors {
-        Server(status: u16, text: String) {
-            description("HTTP Error")
-            display("HTTP Error: {}: {}", status, text)
+    error_chain! {
+        types {
+            Error, ErrorKind, ResultExt, Result;
+        }
+
+        errors {
+            Server(status: u16, text: String) {
+                description("HTTP Error")
+                display("HTTP Error: {}: {}", status, text)
+            }
+            Conflict(name: String, existing: String, requested: String) {
+                description("tunnel already exists with a different config")
+                display("tunnel {} already exists with a different config: existing {}, requested {}", name, existing, requested)
+            }
+            Unverifiable(name: String) {
+                description("existing tunnel's protections cannot be verified")
+                display("tunnel {} already exists, but the agent does not report its auth, header, IP or policy options, so they cannot be verified", name)
+            }
+            MalformedResponse(reason: String, body: String) {
+                description("malformed response from the agent")
+                display("malformed response from the agent: {}", reason)
+            }
+            Setup(step: SetupStep, detail: String) {
+                description("could not set up ngrok")
+                display("ngrok setup failed at {} step: {}", step, detail)
+            }
+            AgentUnavailable(addr: String) {
+                description("no ngrok agent answering")
+                display("no ngrok agent answering on {}", addr)
+            }
+            NotAnAgent(addr: String, reason: String) {
+                description("not an ngrok agent")
+                display("{} is not an ngrok agent: {}", addr, reason)
+            }
+            UnsupportedAgent(version: String) {
+                description("unsupported ngrok agent version")
+                display("unsupported ngrok agent version: {}", version)
+            }
+            InvalidSpec(reason: String) {
+                description("invalid tunnel spec")
+                display("invalid tunnel spec: {}", reason)
+            }
+            UpstreamUnavailable(addr: String, tried: Vec<String>) {
+                description("tunnel upstream unavailable")
+                display("upstream {} unavailable, tried: {}", addr, tried.join("; "))
+            }
+            NoMatchingRequest(tunnel: String, arrived: Vec<String>) {
+                description("no matching request arrived")
+                display("no matching request arrived on tunnel {}; got: [{}]", tunnel, arrived.join(", "))
+            }
+            Startup(err: StartupError) {
+                description("ngrok agent failed to start")
+                display("ngrok agent failed to start: {}", err)
+            }
+        }
+        foreign_links {
+            UReq(Box<ureq::Error>);
         }
-    }
-    foreign_links {
-        UReq(ureq::Error);
     }
 }
+pub use errors::{Error, ErrorKind, Result, ResultExt};
 use serde::Deserialize;
 
+/// The part of fetching and launching the agent that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupStep {
+    Fetch,
+    Write,
+    Extract,
+    Chmod,
+    Spawn,
+}
+
+impl std::fmt::Display for SetupStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SetupStep::Fetch => "fetch",
+            SetupStep::Write => "write",
+            SetupStep::Extract => "extract",
+            SetupStep::Chmod => "chmod",
+            SetupStep::Spawn => "spawn",
+        };
+        f.write_str(name)
+    }
+}
+
+mod addr;
+pub mod api;
+mod auth;
+mod capture;
+mod events;
+mod headers;
+mod ip;
+mod policy;
+mod pool;
+pub mod prometheus;
+mod serve;
+mod shared;
+mod spec;
+mod start;
+mod startup;
+mod supervisor;
+mod tls;
+pub mod transport;
+mod upstream;
+mod version;
+mod watcher;
+pub use addr::Addr;
+pub use api::AgentConfig;
+pub use auth::{Credentials, OAuth, Oidc};
+pub use capture::{CapturedRequest, RequestMatch, RequestPredicate};
+pub use events::{TunnelEvent, TunnelEvents};
+pub use headers::{Header, HeaderRules, HostHeader};
+pub use ip::IpRestriction;
+pub use ipnet::IpNet;
+pub use policy::{Action, Rule, TrafficPolicy};
+pub use pool::{AgentPool, PooledNgrok};
+pub use serve::{ServeOptions, TunnelGuard};
+pub use shared::SharedAgent;
+pub use spec::{BindTls, ConflictPolicy, Proto, TunnelSpec};
+pub use start::{
+    agent_config_path, default_config_path, free_port, runtime_dir, write_config, Probe,
+    StartPolicy, Started,
+};
+use startup::AgentOutput;
+pub use startup::{AgentFailure, StartupError};
+pub use supervisor::Supervisor;
+pub use tls::TlsTermination;
+use transport::{Method, Request, Response};
+pub use transport::{Transport, UreqTransport};
+pub use upstream::UpstreamCheck;
+pub use version::{parse_version, AgentStatus, AgentVersion};
+pub use watcher::{History, MetricsDelta, MetricsSample, MetricsWatcher};
+
 static BASE_URL_STR: &str = "http://127.0.0.1:4040";
 static NGROK_WIN64: &str = "https://bin.equinox.io/c/4VmDzA7iaHb/ngrok-stable-windows-amd64.zip";
 static NGROK_WIN32: &str = "https://bin.equinox.io/c/4VmDzA7iaHb/ngrok-stable-windows-386.zip";
@@ -45,59 +169,98 @@ static NGROK_ARMLINUX32: &str = "https://bin.equinox.io/c/4VmDzA7iaHb/ngrok-stab
 static NGROK_FREEBSD: &str = "https://bin.equinox.io/c/4VmDzA7iaHb/ngrok-stable-freebsd-amd64.zip";
 static NGROK_FREEBSD32: &str = "https://bin.equinox.io/c/4VmDzA7iaHb/ngrok-stable-freebsd-386.zip";
 
-#[derive(Debug, Deserialize)]
+/// Counter with 1, 5 and 15 minute rates and duration percentiles, as
+/// reported by the agent (percentiles are in nanoseconds).
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 pub struct BaseMetric {
-    count: u64,
-    rate1: f64,
-    rate5: f64,
-    rate15: f64,
-    p50: f64,
-    p90: f64,
-    p95: f64,
-    p99: f64,
+    pub count: u64,
+    pub rate1: f64,
+    pub rate5: f64,
+    pub rate15: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub p99: f64,
 }
-#[derive(Debug, Deserialize)]
+/// A `BaseMetric` with the current value of the gauge.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 pub struct GaugeMetric {
-    count: u64,
-    rate1: f64,
-    rate5: f64,
-    rate15: f64,
-    p50: f64,
-    p90: f64,
-    p95: f64,
-    p99: f64,
-    gauge: f64,
+    pub count: u64,
+    pub rate1: f64,
+    pub rate5: f64,
+    pub rate15: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub gauge: f64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 pub struct Metrics {
-    conns: GaugeMetric,
-    http: BaseMetric,
+    pub conns: GaugeMetric,
+    pub http: BaseMetric,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct TunnelConfig {
-    addr: String,
-    inspect: bool,
+    pub addr: Addr,
+    pub inspect: bool,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Tunnel {
-    name: String,
-    uri: String,
-    public_url: String,
-    proto: String,
-    config: TunnelConfig,
-    metrics: Metrics,
+    /// Only reported by v3 agents.
+    #[serde(default, rename = "ID")]
+    pub id: Option<String>,
+    pub name: String,
+    pub uri: String,
+    pub public_url: String,
+    pub proto: String,
+    pub config: TunnelConfig,
+    pub metrics: Metrics,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Tunnels {
-    tunnels: Vec<Tunnel>,
+    pub tunnels: Vec<Tunnel>,
+}
+
+impl TunnelConfig {
+    /// The local service forwarded to, without the scheme the agent may
+    /// prefix (`tcp://localhost:22` gives `localhost:22`).
+    pub fn local_target(&self) -> Addr {
+        self.addr.normalize()
+    }
+}
+
+impl Tunnel {
+    /// The public address of the tunnel, resolved. Mostly useful for tcp
+    /// tunnels, whose `public_url` looks like `tcp://0.tcp.ngrok.io:12345`.
+    pub fn socket_addr(&self) -> Result<SocketAddr> {
+        let url = Url::parse(&self.public_url)
+            .chain_err(|| format!("bad public url {}", self.public_url))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::from(format!("no host in {}", self.public_url)))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| Error::from(format!("no port in {}", self.public_url)))?;
+        (host, port)
+            .to_socket_addrs()
+            .chain_err(|| format!("could not resolve {}", host))?
+            .next()
+            .ok_or_else(|| format!("{} has no address", host).into())
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Ngrok {
     base_url: Url,
+    agent_version: Arc<OnceLock<AgentVersion>>,
+    transport: Arc<dyn Transport>,
+    /// The specs this client (or a clone) created tunnels from, with their
+    /// public URL, by tunnel name.
+    created: Arc<Mutex<HashMap<String, (TunnelSpec, String)>>>,
 }
 pub fn find_file_in_path<P>(exe_name: P) -> Option<PathBuf>
 where
@@ -117,296 +280,297 @@ where
     })
 }
 
+impl Default for Ngrok {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Ngrok {
     pub fn new() -> Self {
+        Self::with_transport(UreqTransport::default())
+    }
+
+    /// A client sending its requests through `transport`.
+    pub fn with_transport<T>(transport: T) -> Self
+    where
+        T: Transport + 'static,
+    {
         Ngrok {
             base_url: Url::parse(BASE_URL_STR).unwrap(),
+            agent_version: Arc::new(OnceLock::new()),
+            transport: Arc::new(transport),
+            created: Arc::default(),
         }
     }
 
+    /// The same client, talking to the agent whose web interface listens
+    /// on `web_addr`.
+    pub fn with_web_addr(mut self, web_addr: SocketAddr) -> Self {
+        self.base_url = Url::parse(&format!("http://{}", web_addr)).unwrap();
+        self.agent_version = Arc::new(OnceLock::new());
+        self.created = Arc::default();
+        self
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
     pub fn exe_name(&self) -> String {
         format!("ngrok{}", env::consts::EXE_SUFFIX)
     }
+    /// Attach to the agent on our web address, or spawn one there when
+    /// nothing listens (`StartPolicy::default()`). Something else answering
+    /// there is an error: use `start_with` to spawn on another port.
     pub fn start(&self) -> Result<(Tunnels, Option<process::Child>)> {
-        let mut child: Option<process::Child> = None;
-        for probe in 0..7 {
-            debug!("STARTING ATTEMPT: {}", probe);
-
-            let tunnels = match self.get::<Tunnels>("api/tunnels") {
-                Ok(resp) => Ok(resp),
-                Err(err) => {
-                    match child {
-                        None => {
-                            debug!("NO THREAD");
-                            if let Ok(handle) = self.start_server() {
-                                debug!("SERVER STARTED!!");
-                                child = Some(handle);
-                            } else {
-                                debug!("FAIL!!")
-                            }
-                        }
-                        Some(_) => {
-                            debug!("ALREADY STARTED");
-                        }
-                    }
-                    Err(err)
-                }
-            };
-            if let Ok(tunnels) = tunnels {
-                return Ok((tunnels, child));
-            } else {
-                let ten_millis = time::Duration::from_millis(10);
-                let now = time::Instant::now();
-                thread::sleep(ten_millis);
-                debug!("now elapsed: {:?}", now.elapsed());
-            }
+        if let Probe::Foreign(reason) = self.probe() {
+            return Err(ErrorKind::NotAnAgent(self.base_url.to_string(), reason).into());
         }
-        Err(Error::from_kind(ErrorKind::Msg(
-            "could not start ngrok".to_owned(),
-        )))
+        let started = self.start_with(StartPolicy::default())?;
+        Ok((started.tunnels, started.child))
     }
 
-    pub fn start_server(&self) -> Result<process::Child> {
-        let path = match find_file_in_path(self.exe_name()) {
+    /// The ngrok executable in `PATH`, downloaded when there is none.
+    pub fn executable(&self) -> Result<PathBuf> {
+        match find_file_in_path(self.exe_name()) {
             None => {
                 debug!("no ngrok executable found");
                 self.download()
             }
-            Some(path) => Some(path),
-        };
-        debug!("START-SERVER: {:#?}", path);
-        let res = match path {
-            Some(path) => {
-                info!("launching ngrok: {}", path.to_string_lossy());
-                let proc = process::Command::new(path)
-                    .args(&["start", "--none"])
-                    .spawn()
-                    .expect("ngrok failed to start");
-                info!("ngrok started: {:#?}", proc);
-
-                //     let ecode = proc.wait()
-                //         .expect("failed to wait on child");
-                //     debug!("ret code: {}", ecode);
-                debug!("CHILD: {:#?}", proc);
-                Ok(proc)
-            }
-            None => {
-                debug!("could not find ngrok executable");
-                Err(Error::from_kind(ErrorKind::Msg("fooo".to_owned())))
+            Some(path) => Ok(path),
+        }
+    }
+
+    pub fn start_server(&self) -> Result<process::Child> {
+        let (child, _output) = spawn_agent(&self.executable()?, &[], None)?;
+        Ok(child)
+    }
+
+    fn request(&self, method: Method, path: &str, data: Option<Value>) -> Result<Response> {
+        let url = self.base_url.join(path).chain_err(|| "oups1")?;
+        let mut headers = Vec::new();
+        let body = match data {
+            Some(data) => {
+                headers.push(("Content-Type".to_owned(), "application/json".to_owned()));
+                Some(serde_json::to_vec(&data).chain_err(|| "could not serialize request")?)
             }
+            None => None,
         };
-        res
+        self.transport.send(Request {
+            method,
+            url,
+            headers,
+            body,
+        })
+    }
+
+    fn server_error(resp: Response) -> Error {
+        let status = resp.status;
+        let text = resp.status_text.clone();
+        let body = resp.text();
+        error!("code: {}: {}\nbody: {}", status, text, body);
+        Error::from_kind(ErrorKind::Server(status, text))
     }
 
     pub fn get<T>(&self, path: &str) -> Result<T>
     where
         T: std::fmt::Debug + for<'de> Deserialize<'de>,
     {
-        match self.base_url.join(path).chain_err(|| "oups1") {
-            Ok(url) => match ureq::get(url.as_str()).call() {
-                Ok(resp) => {
-                    assert!(resp.has("Content-Length"));
-                    let len = resp
-                        .header("Content-Length")
-                        .and_then(|s| s.parse::<usize>().ok())
-                        .unwrap();
-
-                    let mut bytes: Vec<u8> = Vec::with_capacity(len);
-                    resp.into_reader()
-                        .read_to_end(&mut bytes)
-                        .chain_err(|| "unable to read data");
-                    assert_eq!(bytes.len(), len);
-                    let string = str::from_utf8(&bytes);
-                    match string {
-                        Ok(string) => match serde_json::from_str::<Value>(string) {
-                            Ok(json) => match serde_json::from_value::<T>(json.to_owned()) {
-                                Ok(res) => Ok(res),
-                                Err(err) => {
-                                    debug!("Error: {}", err);
-                                    debug!("RAW: {:#?}", json);
-                                    Err(Error::from_kind(ErrorKind::Msg("huu ...".to_string())))
-                                }
-                            },
-                            Err(e) => Err(Error::from_kind(ErrorKind::Msg("huu huu".to_string()))),
-                        },
-                        Err(e) => Err(Error::from_kind(ErrorKind::Msg(e.to_string()))),
-                    }
-                }
-                Err(err) => {
-                    debug!("get err: {:?}", err);
-                    Err(Error::from("get err"))
-                }
-            },
-            Err(err) => Err(err),
+        let resp = self.request(Method::Get, path, None)?;
+        match resp.status {
+            200 => resp.json(),
+            _ => Err(Self::server_error(resp)),
         }
     }
     pub fn post<T>(&self, path: &str, data: Value) -> Result<T>
     where
         T: std::fmt::Debug + for<'de> Deserialize<'de>,
     {
-        match self.base_url.join(path).chain_err(|| "oups1") {
-            Ok(url) => match ureq::post(url.as_str()).send_json(data) {
-                Ok(resp) => match resp.status() {
-                    200 | 201 => {
-                        assert!(resp.has("Content-Length"));
-                        let len = resp
-                            .header("Content-Length")
-                            .and_then(|s| s.parse::<usize>().ok())
-                            .unwrap();
-
-                        let mut bytes: Vec<u8> = Vec::with_capacity(len);
-                        resp.into_reader()
-                            .read_to_end(&mut bytes)
-                            .chain_err(|| "unable to read data");
-                        assert_eq!(bytes.len(), len);
-                        let string = str::from_utf8(&bytes);
-                        match string {
-                            Ok(string) => match serde_json::from_str::<Value>(string) {
-                                Ok(json) => match serde_json::from_value::<T>(json.to_owned()) {
-                                    Ok(res) => Ok(res),
-                                    Err(err) => {
-                                        debug!("Error: {}", err);
-                                        debug!("RAW: {:#?}", json);
-                                        Err(Error::from_kind(ErrorKind::Msg("huu ...".to_string())))
-                                    }
-                                },
-                                Err(e) => {
-                                    Err(Error::from_kind(ErrorKind::Msg("huu huu".to_string())))
-                                }
-                            },
-                            Err(e) => Err(Error::from_kind(ErrorKind::Msg(e.to_string()))),
-                        }
-                    }
-                    error => {
-                        let status = resp.status();
-                        let text = resp.status_text().to_owned();
-                        let body: Value = resp.into_json().unwrap();
-                        error!("code: {}: {}\nbody: {:#?}", status, text, body);
-                        Err(Error::from_kind(ErrorKind::Server(status, text)))
-                    }
-                },
-                Err(err) => {
-                    error!("post err: {:?}", err);
-                    Err(Error::from_kind(ErrorKind::UReq(err)))
-                }
-            },
-            Err(err) => Err(err),
+        let resp = self.request(Method::Post, path, Some(data))?;
+        match resp.status {
+            200 | 201 => resp.json(),
+            _ => Err(Self::server_error(resp)),
         }
     }
 
     pub fn delete(&self, path: &str) -> Result<()> {
-        match self.base_url.join(path).chain_err(|| "oups1") {
-            Ok(url) => match ureq::delete(url.as_str()).call() {
-                Ok(resp) => match resp.status() {
-                    204 => Ok(()),
-                    status => {
-                        error!("HTTP CODE! {}", status);
-                        Err(Error::from_kind(ErrorKind::Server(status, "uu".to_owned())))
-                    }
-                },
-                Err(err) => {
-                    error!("post err: {:?}", err);
-                    Err(Error::from_kind(ErrorKind::UReq(err)))
+        let resp = self.request(Method::Delete, path, None)?;
+        match resp.status {
+            204 => {
+                if let Some(name) = path.strip_prefix("api/tunnels/") {
+                    self.created.lock().unwrap().remove(name);
                 }
-            },
-            Err(err) => Err(err),
+                Ok(())
+            }
+            _ => Err(Self::server_error(resp)),
         }
     } // I'd probably grab the environment variable and iterate through it, returning the first matching path:
 
-    pub fn download(&self) -> Option<PathBuf> {
+    /// Download the ngrok release for this platform into the current
+    /// directory and return the path of the executable.
+    pub fn download(&self) -> Result<PathBuf> {
         debug!(
             "DONLOAD ARCH: {}, OS: {}",
             env::consts::ARCH,
             env::consts::OS
         );
         let url = match (env::consts::ARCH, env::consts::OS) {
-            ("x86_64", "windows") => Ok(NGROK_WIN64),
-            ("x86", "windows") => Ok(NGROK_WIN32),
-            (_, "macos") => Ok(NGROK_MACOS),
-            ("x86_64", "linux") => Ok(NGROK_LINUX),
-            ("x86", "linux") => Ok(NGROK_LINUX32),
-            ("aarch64", "linux") => Ok(NGROK_ARMLINUX),
-            ("arm", "linux") => Ok(NGROK_ARMLINUX32),
-            ("x86_64", "freebsd") => Ok(NGROK_FREEBSD),
-            ("x86", "freebsd") => Ok(NGROK_FREEBSD32),
-            (_, _) => Err(Error::from_kind(ErrorKind::Msg("huuu".to_owned()))),
+            ("x86_64", "windows") => NGROK_WIN64,
+            ("x86", "windows") => NGROK_WIN32,
+            (_, "macos") => NGROK_MACOS,
+            ("x86_64", "linux") => NGROK_LINUX,
+            ("x86", "linux") => NGROK_LINUX32,
+            ("aarch64", "linux") => NGROK_ARMLINUX,
+            ("arm", "linux") => NGROK_ARMLINUX32,
+            ("x86_64", "freebsd") => NGROK_FREEBSD,
+            ("x86", "freebsd") => NGROK_FREEBSD32,
+            (arch, os) => {
+                return Err(ErrorKind::Setup(
+                    SetupStep::Fetch,
+                    format!("ngrok is not supported on {} {}", arch, os),
+                )
+                .into())
+            }
         };
         debug!("ngrok dl url: %{:?}", url);
+        self.download_from(url, Path::new("."))
+    }
 
-        match url {
-            Err(err) => {
-                debug!(
-                    "ngrok is not supported on {} {}: {}",
-                    env::consts::ARCH,
-                    env::consts::OS,
-                    err
-                );
-                None
-            }
-            Ok(url) => match ureq::get(url).call() {
-                Ok(resp) => {
-                    let raw_reader = resp.into_reader();
-                    let mut reader = BufReader::new(raw_reader);
-                    let mut buf = [0u8; 1024];
-                    let zip = File::create("ngrok-local.zip").unwrap();
-                    let mut writer = BufWriter::new(zip);
-                    while let Ok(n) = reader.read(&mut buf) {
-                        if n == 0 {
-                            break;
-                        }
-                        writer.write(&buf[..n]).unwrap();
-                    }
-                    writer.flush().unwrap();
-                    let zip = File::open("ngrok-local.zip").unwrap();
-                    let reader = BufReader::new(zip);
-                    let path = Path::new(".");
-                    let unz = unzip::Unzipper::new(reader, &path);
-                    let _stats = unz.unzip().unwrap();
-                    let exe_name = format!("ngrok{}", env::consts::EXE_SUFFIX);
-                    let exe_path = PathBuf::from(exe_name);
-                    let exe_path = if exe_path.is_absolute() {
-                        exe_path
-                    } else {
-                        fs::canonicalize(exe_path).unwrap()
-                    };
-                    let exe = File::open(&exe_path).unwrap();
-                    #[cfg(target_os = "linux")]
-                    {
-                        info!("OK Linux");
-                        info!("making {:?} executable", exe);
-                        let metadata = exe.metadata().unwrap();
-                        let mut permissions = metadata.permissions();
-                        debug!("permissions: {:#?}", permissions.mode());
-                        permissions.set_mode(0o700);
-                        assert_eq!(permissions.mode(), 0o700);
-                        debug!("permissions: {:#?}", permissions.mode());
-                        match exe.set_permissions(permissions) {
-                            Ok(res) => {
-                                info!("exec permission set on {:?}: {:?}", exe, res);
-                            }
-                            Err(e) => {
-                                error!("could not make {:?} executable: {:?}", exe, e);
-                            }
-                        };
-                    }
-                    Some(exe_path)
-                }
-                Err(_) => None,
-            },
+    /// Download the zip archive at `url`, extract it into `dir` and return
+    /// the path of the ngrok executable it contained.
+    pub fn download_from(&self, url: &str, dir: &Path) -> Result<PathBuf> {
+        let resp = ureq::get(url)
+            .call()
+            .chain_err(|| setup_error(SetupStep::Fetch, url))?;
+
+        let zip_path = dir.join("ngrok-local.zip");
+        let zip = File::create(&zip_path)
+            .chain_err(|| setup_error(SetupStep::Write, zip_path.display()))?;
+        let mut writer = BufWriter::new(zip);
+        io::copy(&mut BufReader::new(resp.into_reader()), &mut writer)
+            .and_then(|_| writer.flush())
+            .chain_err(|| setup_error(SetupStep::Write, zip_path.display()))?;
+
+        let zip = File::open(&zip_path)
+            .chain_err(|| setup_error(SetupStep::Extract, zip_path.display()))?;
+        unzip::Unzipper::new(BufReader::new(zip), dir)
+            .unzip()
+            .chain_err(|| setup_error(SetupStep::Extract, zip_path.display()))?;
+        let missing = || {
+            setup_error(
+                SetupStep::Extract,
+                format!("no {} in {}", self.exe_name(), url),
+            )
+        };
+        let exe_path = dir.join(self.exe_name());
+        if !exe_path.is_file() {
+            return Err(missing().into());
         }
+        let exe_path = canonical_path(&exe_path).chain_err(missing)?;
+
+        #[cfg(target_os = "linux")]
+        {
+            info!("making {:?} executable", exe_path);
+            let mut permissions = fs::metadata(&exe_path)
+                .chain_err(|| setup_error(SetupStep::Chmod, exe_path.display()))?
+                .permissions();
+            debug!("permissions: {:#?}", permissions.mode());
+            permissions.set_mode(0o700);
+            fs::set_permissions(&exe_path, permissions)
+                .chain_err(|| setup_error(SetupStep::Chmod, exe_path.display()))?;
+        }
+        Ok(exe_path)
     }
 }
 
+/// Run `ngrok start --none` with `extra_args`. Its output goes to `log`
+/// when given, for an agent that may outlive this process, else to pipes
+/// read by this process.
+fn spawn_agent(
+    path: &Path,
+    extra_args: &[&OsStr],
+    log: Option<&Path>,
+) -> Result<(process::Child, AgentOutput)> {
+    info!("launching ngrok: {}", path.to_string_lossy());
+    let mut command = process::Command::new(path);
+    command.args(["start", "--none"]).args(extra_args);
+    match log {
+        Some(log) => {
+            let file =
+                File::create(log).chain_err(|| setup_error(SetupStep::Spawn, log.display()))?;
+            let stderr = file
+                .try_clone()
+                .chain_err(|| setup_error(SetupStep::Spawn, log.display()))?;
+            command.stdout(file).stderr(stderr);
+        }
+        None => {
+            command
+                .stdout(process::Stdio::piped())
+                .stderr(process::Stdio::piped());
+        }
+    }
+    let mut proc = command
+        .spawn()
+        .chain_err(|| setup_error(SetupStep::Spawn, path.display()))?;
+    info!("ngrok started: {:#?}", proc);
+    let output = match log {
+        Some(log) => AgentOutput::logged_to(log),
+        None => AgentOutput::capture(&mut proc),
+    };
+    Ok((proc, output))
+}
+
+/// The error for an agent that exited with `status` during startup.
+fn startup_error(output: &AgentOutput, status: process::ExitStatus) -> Error {
+    let text = output.finish();
+    debug!("ngrok output: {}", text);
+    ErrorKind::Startup(startup::classify(&text, Some(status))).into()
+}
+
+fn setup_error<D: std::fmt::Display>(step: SetupStep, detail: D) -> ErrorKind {
+    ErrorKind::Setup(step, detail.to_string())
+}
+
+/// `path` if it is absolute, else its canonical form (which must exist).
+fn canonical_path(path: &Path) -> io::Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_owned())
+    } else {
+        fs::canonicalize(path)
+    }
+}
+
+/// A tunnel as `api/tunnels` lists it, with zeroed metrics; tests adjust
+/// the fields they care about.
+#[cfg(test)]
+pub(crate) fn tunnel_fixture(name: &str, proto: &str, public_url: &str, addr: &str) -> Value {
+    serde_json::json!({
+        "name": name,
+        "uri": format!("/api/tunnels/{}", name),
+        "public_url": public_url,
+        "proto": proto,
+        "config": {"addr": addr, "inspect": true},
+        "metrics": {
+            "conns": {"count": 0, "gauge": 0, "rate1": 0, "rate5": 0, "rate15": 0,
+                      "p50": 0, "p90": 0, "p95": 0, "p99": 0},
+            "http": {"count": 0, "rate1": 0, "rate5": 0, "rate15": 0,
+                     "p50": 0, "p90": 0, "p95": 0, "p99": 0}
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{find_file_in_path, Ngrok, Tunnel, Tunnels};
-    use env_logger;
+    use crate::{ErrorKind, Ngrok, SetupStep, Tunnel, Tunnels};
     use log::{debug, error, info, warn};
-    use serde::__private::ser;
     use serde_json::{json, Value};
-    use std::thread;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::Once;
     use std::time;
-    use std::{process, sync::Once};
+    use std::{env, fs, process, thread};
     //    lazy_static! {
     //        statuc FOO = env_logger::init();
     //    }
@@ -438,6 +602,7 @@ mod tests {
                         "addr": 8069,
                         "proto": "http",
                         "bind_tls": "both",
+                        "host_header": "rewrite",
                         "inspect": true
                 });
                 let ota_tunnel = json!({
@@ -532,4 +697,62 @@ mod tests {
             }
         }
     }
+
+    /// Answer a single request on a local port with `status` and `body`.
+    fn serve_once(status: &'static str, body: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ngrok.zip", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            let _ = stream.write_all(body);
+        });
+        url
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ngrok2-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn download_bad_archive() {
+        let url = serve_once("200 OK", b"this is not a zip archive");
+        let dir = scratch_dir("bad-archive");
+        let err = Ngrok::new().download_from(&url, &dir).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::Setup(SetupStep::Extract, _)
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn download_archive_without_ngrok() {
+        let url = serve_once("200 OK", include_bytes!("../tests/fixtures/no-ngrok.zip"));
+        let dir = fs::canonicalize(scratch_dir("no-ngrok")).unwrap();
+        let err = Ngrok::new().download_from(&url, &dir).unwrap_err();
+        match err.kind() {
+            ErrorKind::Setup(SetupStep::Extract, detail) => assert!(detail.starts_with("no ngrok")),
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn download_not_found() {
+        let url = serve_once("404 Not Found", b"");
+        let dir = scratch_dir("not-found");
+        let err = Ngrok::new().download_from(&url, &dir).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Setup(SetupStep::Fetch, _)));
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,286 @@
+//! Traffic policy documents for v3 agents.
+//!
+//! A policy is a list of rules per phase; a rule runs its actions when all
+//! its CEL expressions hold. Action types are an enum, so a document built
+//! here only holds types the agent knows, and parsing one rejects the rest.
+
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::{ErrorKind, Result, ResultExt};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "config", rename_all = "kebab-case")]
+pub enum Action {
+    Deny {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status_code: Option<u16>,
+    },
+    RateLimit {
+        name: String,
+        algorithm: String,
+        capacity: u32,
+        /// Window length, e.g. `60s`.
+        rate: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        bucket_key: Vec<String>,
+    },
+    AddHeaders {
+        headers: BTreeMap<String, String>,
+    },
+    UrlRewrite {
+        from: String,
+        to: String,
+    },
+    CustomResponse {
+        status_code: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+    },
+}
+
+impl Action {
+    pub fn deny(status_code: u16) -> Self {
+        Action::Deny {
+            status_code: Some(status_code),
+        }
+    }
+
+    /// At most `capacity` requests per `seconds` for each client IP.
+    pub fn rate_limit<N: Into<String>>(name: N, capacity: u32, seconds: u64) -> Self {
+        Action::RateLimit {
+            name: name.into(),
+            algorithm: "sliding_window".to_owned(),
+            capacity,
+            rate: format!("{}s", seconds),
+            bucket_key: vec!["conn.client_ip".to_owned()],
+        }
+    }
+
+    pub fn add_headers<I, K, V>(headers: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        Action::AddHeaders {
+            headers: headers
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        }
+    }
+
+    pub fn url_rewrite<F, T>(from: F, to: T) -> Self
+    where
+        F: Into<String>,
+        T: Into<String>,
+    {
+        Action::UrlRewrite {
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+
+    pub fn custom_response<C: Into<String>>(status_code: u16, content: C) -> Self {
+        Action::CustomResponse {
+            status_code,
+            content: Some(content.into()),
+            headers: BTreeMap::new(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Deny { .. } => "deny",
+            Action::RateLimit { .. } => "rate-limit",
+            Action::AddHeaders { .. } => "add-headers",
+            Action::UrlRewrite { .. } => "url-rewrite",
+            Action::CustomResponse { .. } => "custom-response",
+        }
+    }
+
+    /// Actions that only make sense before the upstream is called.
+    fn request_only(&self) -> bool {
+        matches!(self, Action::RateLimit { .. } | Action::UrlRewrite { .. })
+    }
+}
+
+/// Actions without settings may leave out `config`.
+fn deserialize_actions<'de, D>(deserializer: D) -> std::result::Result<Vec<Action>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = Vec::<Value>::deserialize(deserializer)?;
+    values
+        .into_iter()
+        .map(|mut value| {
+            if let Value::Object(ref mut map) = value {
+                map.entry("config")
+                    .or_insert_with(|| Value::Object(Default::default()));
+            }
+            serde_json::from_value(value).map_err(de::Error::custom)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// CEL expressions that must all hold; none means always.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expressions: Vec<String>,
+    #[serde(deserialize_with = "deserialize_actions")]
+    pub actions: Vec<Action>,
+}
+
+impl Rule {
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        Rule {
+            name: Some(name.into()),
+            ..Rule::default()
+        }
+    }
+
+    pub fn expression<E: Into<String>>(mut self, expression: E) -> Self {
+        self.expressions.push(expression.into());
+        self
+    }
+
+    pub fn action(mut self, action: Action) -> Self {
+        self.actions.push(action);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficPolicy {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_http_request: Vec<Rule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_http_response: Vec<Rule>,
+}
+
+impl TrafficPolicy {
+    pub fn on_http_request(mut self, rule: Rule) -> Self {
+        self.on_http_request.push(rule);
+        self
+    }
+
+    pub fn on_http_response(mut self, rule: Rule) -> Self {
+        self.on_http_response.push(rule);
+        self
+    }
+
+    /// Check what the agent would refuse: rules without actions, and
+    /// request-only actions in the response phase.
+    pub fn validate(&self) -> Result<()> {
+        let phases = [
+            ("on_http_request", &self.on_http_request),
+            ("on_http_response", &self.on_http_response),
+        ];
+        for (phase, rules) in phases.iter() {
+            for (index, rule) in rules.iter().enumerate() {
+                let rule_name = rule.name.clone().unwrap_or_else(|| format!("#{}", index));
+                if rule.actions.is_empty() {
+                    return Err(ErrorKind::InvalidSpec(format!(
+                        "{} rule {} has no action",
+                        phase, rule_name
+                    ))
+                    .into());
+                }
+                if *phase == "on_http_response" {
+                    if let Some(action) = rule.actions.iter().find(|a| a.request_only()) {
+                        return Err(ErrorKind::InvalidSpec(format!(
+                            "{} rule {}: {} only applies to requests",
+                            phase,
+                            rule_name,
+                            action.name()
+                        ))
+                        .into());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        self.validate()?;
+        serde_json::to_string_pretty(self).chain_err(|| "could not write traffic policy")
+    }
+
+    pub fn to_yaml(&self) -> Result<String> {
+        self.validate()?;
+        serde_yaml::to_string(self).chain_err(|| "could not write traffic policy")
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        let policy: Self = serde_json::from_str(text)
+            .map_err(|err| ErrorKind::InvalidSpec(format!("traffic policy: {}", err)))?;
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    pub fn from_yaml(text: &str) -> Result<Self> {
+        let policy: Self = serde_yaml::from_str(text)
+            .map_err(|err| ErrorKind::InvalidSpec(format!("traffic policy: {}", err)))?;
+        policy.validate()?;
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Rule, TrafficPolicy};
+    use crate::ErrorKind;
+    use serde_json::json;
+
+    #[test]
+    fn policy_documents() {
+        let policy = TrafficPolicy::default()
+            .on_http_request(
+                Rule::new("no writes")
+                    .expression("req.method == 'DELETE'")
+                    .action(Action::deny(403)),
+            )
+            .on_http_request(Rule::new("throttle").action(Action::rate_limit("erp", 60, 60)))
+            .on_http_response(
+                Rule::new("tag").action(Action::add_headers(vec![("x-served-by", "erp")])),
+            );
+        let value: serde_json::Value = serde_json::from_str(&policy.to_json().unwrap()).unwrap();
+        assert_eq!(
+            value["on_http_request"][0],
+            json!({"name": "no writes", "expressions": ["req.method == 'DELETE'"],
+                   "actions": [{"type": "deny", "config": {"status_code": 403}}]})
+        );
+        assert_eq!(
+            value["on_http_request"][1]["actions"][0]["type"],
+            "rate-limit"
+        );
+        assert_eq!(
+            TrafficPolicy::from_yaml(&policy.to_yaml().unwrap()).unwrap(),
+            policy
+        );
+    }
+
+    #[test]
+    fn rejected_policies() {
+        let unknown =
+            "on_http_request:\n  - actions:\n      - type: teleport\n        config: {}\n";
+        let err = TrafficPolicy::from_yaml(unknown).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidSpec(_)));
+        let misplaced = TrafficPolicy::default()
+            .on_http_response(Rule::new("late").action(Action::url_rewrite("^/a", "/b")));
+        assert!(misplaced.validate().is_err());
+        assert!(TrafficPolicy::default()
+            .on_http_request(Rule::new("empty"))
+            .to_json()
+            .is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! A pool of agents for tests running in parallel on one machine.
+//!
+//! Every agent gets its own web_addr port and config file, so tests stop
+//! fighting over `127.0.0.1:4040`.
+
+use log::{debug, error, info};
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::process;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::{free_port, Ngrok, Result, Tunnels};
+
+#[derive(Debug)]
+struct Agent {
+    ngrok: Ngrok,
+    child: process::Child,
+}
+
+#[derive(Debug)]
+pub struct AgentPool {
+    agents: Vec<Agent>,
+    available: Mutex<Vec<usize>>,
+    returned: Condvar,
+}
+
+impl AgentPool {
+    /// A pool of agents already running, all available.
+    fn with_agents(agents: Vec<Agent>) -> Self {
+        AgentPool {
+            available: Mutex::new((0..agents.len()).collect()),
+            agents,
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Spawn `size` agents on free ports, using `template` (transport,
+    /// binary lookup) for each of them. `size` cannot be 0: nothing could
+    /// ever be checked out.
+    pub fn new(template: &Ngrok, size: usize) -> Result<Self> {
+        if size == 0 {
+            return Err("an agent pool needs at least one agent".into());
+        }
+        let mut pool = Self::with_agents(Vec::with_capacity(size));
+        for _ in 0..size {
+            // on error, dropping the pool stops the agents already started
+            let addr = SocketAddr::from(([127, 0, 0, 1], free_port()?));
+            let started = template.spawn_on(addr)?;
+            let child = started.child.ok_or("spawned agent has no process")?;
+            info!("pooled ngrok agent on {}", started.ngrok.base_url());
+            pool.agents.push(Agent {
+                ngrok: started.ngrok,
+                child,
+            });
+            pool.available.lock().unwrap().push(pool.agents.len() - 1);
+        }
+        Ok(pool)
+    }
+
+    pub fn len(&self) -> usize {
+        self.agents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.agents.is_empty()
+    }
+
+    /// Take an agent out of the pool, waiting for one to be returned if
+    /// they are all in use.
+    pub fn checkout(&self) -> PooledNgrok<'_> {
+        let mut available = self.available.lock().unwrap();
+        loop {
+            if let Some(index) = available.pop() {
+                return PooledNgrok { pool: self, index };
+            }
+            available = self.returned.wait(available).unwrap();
+        }
+    }
+
+    /// Like `checkout`, giving up after `timeout`.
+    pub fn checkout_timeout(&self, timeout: Duration) -> Option<PooledNgrok<'_>> {
+        let available = self.available.lock().unwrap();
+        let (mut available, _) = self
+            .returned
+            .wait_timeout_while(available, timeout, |available| available.is_empty())
+            .unwrap();
+        available
+            .pop()
+            .map(|index| PooledNgrok { pool: self, index })
+    }
+
+    fn checkin(&self, index: usize) {
+        // hand the agent over without the previous user's tunnels
+        let ngrok = &self.agents[index].ngrok;
+        match ngrok.get::<Tunnels>("api/tunnels") {
+            Ok(tunnels) => {
+                for tunnel in tunnels.tunnels {
+                    if let Err(err) = ngrok.delete(&format!("api/tunnels/{}", tunnel.name)) {
+                        debug!("could not delete tunnel {}: {}", tunnel.name, err);
+                    }
+                }
+            }
+            Err(err) => error!("pooled agent {} unreachable: {}", ngrok.base_url(), err),
+        }
+        self.available.lock().unwrap().push(index);
+        self.returned.notify_one();
+    }
+}
+
+impl Drop for AgentPool {
+    fn drop(&mut self) {
+        for agent in &mut self.agents {
+            debug!("stopping pooled agent {}", agent.ngrok.base_url());
+            let _ = agent.child.kill();
+            let _ = agent.child.wait();
+        }
+    }
+}
+
+/// An agent checked out of an `AgentPool`; goes back to the pool, with its
+/// tunnels closed, when dropped.
+#[derive(Debug)]
+pub struct PooledNgrok<'a> {
+    pool: &'a AgentPool,
+    index: usize,
+}
+
+impl Deref for PooledNgrok<'_> {
+    type Target = Ngrok;
+
+    fn deref(&self) -> &Ngrok {
+        &self.pool.agents[self.index].ngrok
+    }
+}
+
+impl Drop for PooledNgrok<'_> {
+    fn drop(&mut self) {
+        self.pool.checkin(self.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Agent, AgentPool};
+    use crate::transport::{Method, Request, Response, Transport};
+    use crate::{tunnel_fixture, Ngrok, Result};
+    use serde_json::json;
+    use std::process::Command;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    /// An agent with one tunnel open, recording deletions.
+    #[derive(Debug, Default)]
+    struct Busy {
+        deleted: Mutex<Vec<String>>,
+    }
+
+    impl Transport for Arc<Busy> {
+        fn send(&self, request: Request) -> Result<Response> {
+            match request.method {
+                Method::Delete => {
+                    self.deleted
+                        .lock()
+                        .unwrap()
+                        .push(request.url.path().to_owned());
+                    Ok(Response::new(204, "No Content", ""))
+                }
+                _ => {
+                    let tunnel = tunnel_fixture("erp", "https", "https://abcd.ngrok.app", "8069");
+                    let body = json!({ "tunnels": [tunnel] });
+                    Ok(Response::new(200, "OK", body.to_string()))
+                }
+            }
+        }
+    }
+
+    fn pool(busy: &Arc<Busy>, size: usize) -> AgentPool {
+        let agents = (0..size)
+            .map(|_| Agent {
+                ngrok: Ngrok::with_transport(Arc::clone(busy)),
+                // stands in for the agent process
+                child: Command::new("sleep").arg("60").spawn().unwrap(),
+            })
+            .collect();
+        AgentPool::with_agents(agents)
+    }
+
+    #[test]
+    fn checkout_and_return() {
+        let busy = Arc::new(Busy::default());
+        let pool = pool(&busy, 2);
+        let first = pool.checkout();
+        let second = pool.checkout_timeout(Duration::from_millis(10)).unwrap();
+        assert!(pool.checkout_timeout(Duration::from_millis(10)).is_none());
+        drop(second);
+        assert_eq!(*busy.deleted.lock().unwrap(), ["/api/tunnels/erp"]);
+        let third = pool.checkout_timeout(Duration::from_millis(10)).unwrap();
+
+        // checkout waits until an agent comes back
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| pool.checkout().base_url().clone());
+            thread::sleep(Duration::from_millis(50));
+            drop(first);
+            waiting.join().unwrap();
+        });
+        drop(third);
+        assert_eq!(busy.deleted.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn empty_pools_are_refused() {
+        assert!(AgentPool::new(&Ngrok::new(), 0).is_err());
+    }
+}
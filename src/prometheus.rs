@@ -0,0 +1,280 @@
+//! Render tunnel metrics in the Prometheus text exposition format.
+//!
+//! `render` turns a `Tunnels` snapshot into text; `serve` answers
+//! `GET /metrics` with a fresh snapshot of the agent on each scrape.
+
+use log::{debug, error};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::{BaseMetric, Ngrok, Tunnel, Tunnels};
+
+type Percentile = (&'static str, fn(&BaseMetric) -> f64);
+
+/// Not `quantile`: Prometheus reserves that label for summaries, which also
+/// need a sum the agent does not report.
+const PERCENTILES: [Percentile; 4] = [
+    ("50", |m| m.p50),
+    ("90", |m| m.p90),
+    ("95", |m| m.p95),
+    ("99", |m| m.p99),
+];
+/// Request headers read before giving up on the blank line ending them.
+const MAX_HEADER_LINES: usize = 100;
+/// How long a client may take to send its request or read the answer.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn labels(tunnel: &Tunnel, extra: Option<(&str, &str)>) -> String {
+    let mut labels = format!(
+        "tunnel=\"{}\",proto=\"{}\"",
+        escape(&tunnel.name),
+        escape(&tunnel.proto)
+    );
+    if let Some((key, value)) = extra {
+        let _ = write!(labels, ",{}=\"{}\"", key, value);
+    }
+    labels
+}
+
+fn conns(tunnel: &Tunnel) -> BaseMetric {
+    let m = &tunnel.metrics.conns;
+    BaseMetric {
+        count: m.count,
+        rate1: m.rate1,
+        rate5: m.rate5,
+        rate15: m.rate15,
+        p50: m.p50,
+        p90: m.p90,
+        p95: m.p95,
+        p99: m.p99,
+    }
+}
+
+fn http(tunnel: &Tunnel) -> BaseMetric {
+    tunnel.metrics.http
+}
+
+type Kind = (&'static str, &'static str, fn(&Tunnel) -> BaseMetric);
+
+const KINDS: [Kind; 2] = [
+    ("conns", "connections", conns),
+    ("http", "HTTP requests", http),
+];
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Render the per-tunnel `conns` and `http` metrics of `tunnels`.
+///
+/// Counts are exported as counters, rates as gauges labelled by window, and
+/// the duration percentiles as gauges in seconds labelled by percentile.
+pub fn render(tunnels: &Tunnels) -> String {
+    let mut out = String::new();
+    for (kind, what, base) in &KINDS {
+        let name = format!("ngrok_tunnel_{}_total", kind);
+        family(&mut out, &name, "counter", &format!("Total {}.", what));
+        for tunnel in &tunnels.tunnels {
+            let m = base(tunnel);
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels(tunnel, None), m.count);
+        }
+
+        let name = format!("ngrok_tunnel_{}_rate", kind);
+        family(
+            &mut out,
+            &name,
+            "gauge",
+            &format!("Moving average of {} per second.", what),
+        );
+        for tunnel in &tunnels.tunnels {
+            let m = base(tunnel);
+            for (window, rate) in &[("1m", m.rate1), ("5m", m.rate5), ("15m", m.rate15)] {
+                let _ = writeln!(
+                    out,
+                    "{}{{{}}} {}",
+                    name,
+                    labels(tunnel, Some(("window", window))),
+                    rate
+                );
+            }
+        }
+
+        let name = format!("ngrok_tunnel_{}_duration_seconds", kind);
+        family(
+            &mut out,
+            &name,
+            "gauge",
+            &format!("Duration percentiles of {}.", what),
+        );
+        for tunnel in &tunnels.tunnels {
+            let m = base(tunnel);
+            for (percentile, get) in &PERCENTILES {
+                let _ = writeln!(
+                    out,
+                    "{}{{{}}} {}",
+                    name,
+                    labels(tunnel, Some(("percentile", percentile))),
+                    get(&m) / 1e9
+                );
+            }
+        }
+    }
+
+    let name = "ngrok_tunnel_conns_open";
+    family(&mut out, name, "gauge", "Currently open connections.");
+    for tunnel in &tunnels.tunnels {
+        let _ = writeln!(
+            out,
+            "{}{{{}}} {}",
+            name,
+            labels(tunnel, None),
+            tunnel.metrics.conns.gauge
+        );
+    }
+    out
+}
+
+fn respond(ngrok: &Ngrok, mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    debug!("metrics request: {}", request_line.trim_end());
+    // read the headers up to the blank line, or closing the connection with
+    // them unread resets it before the client has the response
+    let mut header = String::new();
+    for _ in 0..MAX_HEADER_LINES {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        match ngrok.get::<Tunnels>("api/tunnels") {
+            Ok(tunnels) => ("200 OK", render(&tunnels)),
+            Err(err) => {
+                error!("could not get tunnels: {}", err);
+                ("503 Service Unavailable", format!("{}\n", err))
+            }
+        }
+    } else {
+        ("404 Not Found", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Serve `GET /metrics` on `listener` from a background thread, querying
+/// the agent behind `ngrok` on every scrape. Each connection gets its own
+/// thread, so a client that never sends its request holds up nobody else.
+pub fn serve(ngrok: Ngrok, listener: TcpListener) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let ngrok = ngrok.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = respond(&ngrok, stream) {
+                            error!("metrics endpoint: {}", err);
+                        }
+                    });
+                }
+                Err(err) => error!("metrics endpoint: {}", err),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, serve};
+    use crate::transport::{Request, Response, Transport};
+    use crate::{tunnel_fixture, Ngrok, Result, Tunnels};
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    /// Lists one tunnel.
+    #[derive(Debug)]
+    struct Agent;
+
+    impl Transport for Agent {
+        fn send(&self, _request: Request) -> Result<Response> {
+            let tunnel = tunnel_fixture("erp", "https", "https://abcd.ngrok.io", "8069");
+            let body = json!({ "tunnels": [tunnel] });
+            Ok(Response::new(200, "OK", body.to_string()))
+        }
+    }
+
+    #[test]
+    fn render_tunnel_metrics() {
+        let mut tunnel = tunnel_fixture(
+            "erp",
+            "https",
+            "https://abcd.ngrok.io",
+            "http://localhost:8069",
+        );
+        let conns = &mut tunnel["metrics"]["conns"];
+        conns["count"] = json!(3);
+        conns["gauge"] = json!(1);
+        conns["rate1"] = json!(0.5);
+        conns["rate5"] = json!(0.1);
+        conns["p50"] = json!(1500000000.0);
+        tunnel["metrics"]["http"]["count"] = json!(7);
+        tunnel["metrics"]["http"]["p99"] = json!(250000000.0);
+        let tunnels: Tunnels = serde_json::from_value(json!({ "tunnels": [tunnel] })).unwrap();
+        let text = render(&tunnels);
+        assert!(text.contains("# TYPE ngrok_tunnel_conns_total counter\n"));
+        assert!(text.contains("ngrok_tunnel_conns_total{tunnel=\"erp\",proto=\"https\"} 3\n"));
+        assert!(text.contains("ngrok_tunnel_http_total{tunnel=\"erp\",proto=\"https\"} 7\n"));
+        assert!(text.contains(
+            "ngrok_tunnel_conns_rate{tunnel=\"erp\",proto=\"https\",window=\"1m\"} 0.5\n"
+        ));
+        assert!(text.contains(
+            "ngrok_tunnel_conns_duration_seconds{tunnel=\"erp\",proto=\"https\",percentile=\"50\"} 1.5\n"
+        ));
+        assert!(text.contains(
+            "ngrok_tunnel_http_duration_seconds{tunnel=\"erp\",proto=\"https\",percentile=\"99\"} 0.25\n"
+        ));
+        assert!(text.contains("ngrok_tunnel_conns_open{tunnel=\"erp\",proto=\"https\"} 1\n"));
+    }
+
+    #[test]
+    fn serves_scrapes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve(Ngrok::with_transport(Agent), listener);
+        // connects and sends nothing
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET /metrics HTTP/1.1\r\nHost: {}\r\nUser-Agent: Prometheus/2.53.0\r\n\
+             Accept: text/plain;version=0.0.4\r\nX-Prometheus-Scrape-Timeout-Seconds: 10\r\n\r\n",
+            addr
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("ngrok_tunnel_conns_total{tunnel=\"erp\",proto=\"https\"} 0\n"));
+    }
+}
@@ -0,0 +1,304 @@
+//! Short-lived tunnels that close themselves.
+//!
+//! `TunnelGuard` holds a tunnel and removes it from the agent when dropped,
+//! or earlier when given an expiry. `Ngrok::serve_dir` uses it to share a
+//! directory through the agent's built-in file server, and
+//! `Ngrok::expose_listener` to publish a server bound in-process, e.g. by a
+//! test.
+
+use log::{debug, info, warn};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::spec::{is_not_found, tunnel_path};
+use crate::{
+    Addr, BindTls, Credentials, ErrorKind, Ngrok, Proto, Result, ResultExt, Tunnel, TunnelSpec,
+};
+
+static NEXT_NAME: AtomicUsize = AtomicUsize::new(0);
+
+/// A tunnel name no other guard of this process uses.
+fn unique_name(prefix: &str) -> String {
+    let n = NEXT_NAME.fetch_add(1, Ordering::Relaxed);
+    format!("{}-{}-{}", prefix, process::id(), n)
+}
+
+/// Delete `name`, and its `(http)` companion when there is one.
+fn remove(ngrok: &Ngrok, name: &str) -> Result<()> {
+    for path in &[tunnel_path(name), tunnel_path(&format!("{} (http)", name))] {
+        if let Err(err) = ngrok.delete(path) {
+            if !is_not_found(&err) {
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A tunnel removed from the agent when the guard is dropped.
+#[derive(Debug)]
+pub struct TunnelGuard {
+    ngrok: Ngrok,
+    tunnel: Tunnel,
+    open: bool,
+    stop: Option<Sender<()>>,
+    expiry: Option<thread::JoinHandle<()>>,
+}
+
+impl TunnelGuard {
+    pub fn new(ngrok: &Ngrok, tunnel: Tunnel) -> Self {
+        TunnelGuard {
+            ngrok: ngrok.clone(),
+            tunnel,
+            open: true,
+            stop: None,
+            expiry: None,
+        }
+    }
+
+    /// Also remove the tunnel once `after` has elapsed.
+    pub fn expire(mut self, after: Duration) -> Self {
+        let ngrok = self.ngrok.clone();
+        let name = self.tunnel.name.clone();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        self.stop = Some(stop_tx);
+        self.expiry = Some(thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(after) {
+                info!("tunnel {} expired", name);
+                if let Err(err) = remove(&ngrok, &name) {
+                    warn!("could not remove expired tunnel {}: {}", name, err);
+                }
+            }
+        }));
+        self
+    }
+
+    pub fn tunnel(&self) -> &Tunnel {
+        &self.tunnel
+    }
+
+    pub fn public_url(&self) -> &str {
+        &self.tunnel.public_url
+    }
+
+    /// Remove the tunnel now, reporting failures the drop only logs.
+    pub fn close(mut self) -> Result<()> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<()> {
+        self.open = false;
+        self.stop.take();
+        if let Some(expiry) = self.expiry.take() {
+            let _ = expiry.join();
+        }
+        debug!("removing tunnel {}", self.tunnel.name);
+        remove(&self.ngrok, &self.tunnel.name)
+    }
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        if !self.open {
+            return;
+        }
+        if let Err(err) = self.release() {
+            warn!("could not remove tunnel {}: {}", self.tunnel.name, err);
+        }
+    }
+}
+
+/// Options of `Ngrok::serve_dir`.
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    pub name: Option<String>,
+    pub basic_auth: Option<Credentials>,
+    pub expires_in: Option<Duration>,
+}
+
+impl ServeOptions {
+    pub fn name<N: Into<String>>(mut self, name: N) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn basic_auth(mut self, credentials: Credentials) -> Self {
+        self.basic_auth = Some(credentials);
+        self
+    }
+
+    pub fn expires_in(mut self, expires_in: Duration) -> Self {
+        self.expires_in = Some(expires_in);
+        self
+    }
+}
+
+/// The absolute path of the directory `path`.
+fn served_dir(path: &Path) -> Result<PathBuf> {
+    let dir = fs::canonicalize(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => {
+            ErrorKind::InvalidSpec(format!("{}: no such directory", path.display()))
+        }
+        _ => ErrorKind::InvalidSpec(format!("{}: {}", path.display(), err)),
+    })?;
+    if !dir.is_dir() {
+        return Err(ErrorKind::InvalidSpec(format!("{}: not a directory", dir.display())).into());
+    }
+    Ok(dir)
+}
+
+impl Ngrok {
+    /// Serve the files under `path` on an https tunnel until the returned
+    /// guard is dropped or `options.expires_in` has elapsed.
+    pub fn serve_dir<P: AsRef<Path>>(&self, path: P, options: ServeOptions) -> Result<TunnelGuard> {
+        let dir = served_dir(path.as_ref())?;
+        let name = options.name.unwrap_or_else(|| unique_name("files"));
+        let mut spec = TunnelSpec::http(name, Addr::File(dir)).bind_tls(BindTls::Https);
+        if let Some(credentials) = options.basic_auth {
+            spec = spec.basic_auth(credentials);
+        }
+        let tunnel = self.create_tunnel(&spec)?;
+        info!("serving {} on {}", spec.addr, tunnel.public_url);
+        let guard = TunnelGuard::new(self, tunnel);
+        Ok(match options.expires_in {
+            Some(after) => guard.expire(after),
+            None => guard,
+        })
+    }
+
+    /// Tunnel to the server accepting on `listener` until the returned
+    /// guard is dropped. http tunnels only get an https endpoint.
+    pub fn expose_listener(&self, listener: &TcpListener, proto: Proto) -> Result<TunnelGuard> {
+        let local_addr = listener
+            .local_addr()
+            .chain_err(|| "listener has no local address")?;
+        let spec = TunnelSpec::new(unique_name(proto.as_str()), proto, connectable(local_addr));
+        let spec = match proto {
+            Proto::Http => spec.bind_tls(BindTls::Https),
+            _ => spec,
+        };
+        let tunnel = self.create_tunnel(&spec)?;
+        info!("exposing {} on {}", local_addr, tunnel.public_url);
+        Ok(TunnelGuard::new(self, tunnel))
+    }
+}
+
+/// Where the agent can reach a listener bound to `addr`: loopback for the
+/// unspecified address.
+fn connectable(addr: SocketAddr) -> Addr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    Addr::from(SocketAddr::new(ip, addr.port()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServeOptions;
+    use crate::transport::{Method, Request, Response, Transport};
+    use crate::{tunnel_fixture, Credentials, ErrorKind, Ngrok, Proto, Result};
+    use serde_json::{json, Value};
+    use std::env;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A v3 agent that echoes created tunnels and records deletions.
+    #[derive(Debug, Default)]
+    struct Agent {
+        created: Mutex<Vec<Value>>,
+        deleted: Mutex<Vec<String>>,
+    }
+
+    impl Transport for Arc<Agent> {
+        fn send(&self, request: Request) -> Result<Response> {
+            match (request.method, request.url.path()) {
+                (Method::Get, "/api/status") => {
+                    Ok(Response::new(200, "OK", r#"{"version": "3.5.0"}"#))
+                }
+                (Method::Post, "/api/tunnels") => {
+                    let body: Value = serde_json::from_slice(&request.body.unwrap()).unwrap();
+                    self.created.lock().unwrap().push(body.clone());
+                    let mut tunnel = tunnel_fixture(
+                        body["name"].as_str().unwrap(),
+                        "https",
+                        "https://abcd.ngrok.app",
+                        body["addr"].as_str().unwrap(),
+                    );
+                    tunnel["ID"] = json!("1");
+                    Ok(Response::new(201, "Created", tunnel.to_string()))
+                }
+                (Method::Delete, path) if !path.ends_with("(http)") => {
+                    self.deleted.lock().unwrap().push(path.to_owned());
+                    Ok(Response::new(204, "No Content", ""))
+                }
+                _ => Ok(Response::new(404, "Not Found", "{}")),
+            }
+        }
+    }
+
+    #[test]
+    fn served_dirs_are_removed() {
+        let agent = Arc::new(Agent::default());
+        let ngrok = Ngrok::with_transport(Arc::clone(&agent));
+        let options = ServeOptions::default()
+            .name("dist")
+            .basic_auth(Credentials::new("ci", "artifacts").unwrap());
+        let guard = ngrok.serve_dir(env::temp_dir(), options).unwrap();
+        assert_eq!(guard.public_url(), "https://abcd.ngrok.app");
+        let created = agent.created.lock().unwrap()[0].clone();
+        assert!(created["addr"].as_str().unwrap().starts_with("file:///"));
+        assert_eq!(created["schemes"], json!(["https"]));
+        assert_eq!(created["basic_auth"], json!(["ci:artifacts"]));
+        drop(guard);
+        assert!(agent.deleted.lock().unwrap()[0].ends_with("/dist"));
+
+        let guard = ngrok
+            .serve_dir(
+                env::temp_dir(),
+                ServeOptions::default().expires_in(Duration::from_millis(10)),
+            )
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while agent.deleted.lock().unwrap().len() < 2 {
+            assert!(Instant::now() < deadline, "tunnel did not expire");
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(guard);
+
+        for missing in &["no/such/dir", "/no/such/dir"] {
+            let err = ngrok
+                .serve_dir(missing, ServeOptions::default())
+                .unwrap_err();
+            match err.kind() {
+                ErrorKind::InvalidSpec(detail) => assert!(detail.ends_with("no such directory")),
+                kind => panic!("unexpected error: {:?}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn exposed_listeners() {
+        let agent = Arc::new(Agent::default());
+        let ngrok = Ngrok::with_transport(Arc::clone(&agent));
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let guard = ngrok.expose_listener(&listener, Proto::Tcp).unwrap();
+        let created = agent.created.lock().unwrap()[0].clone();
+        assert_eq!(created["proto"], "tcp");
+        assert_eq!(created["addr"], format!("127.0.0.1:{}", port));
+        assert_eq!(created["schemes"], Value::Null);
+        guard.close().unwrap();
+        assert_eq!(agent.deleted.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,236 @@
+//! One agent shared by several processes, e.g. concurrent `cargo test`
+//! binaries.
+//!
+//! The agent's PID and web_addr are recorded in a state file in the
+//! runtime dir, along with the PIDs of the processes using it. Every
+//! change to that file happens under an exclusive lock on a sibling lock
+//! file. The last user to leave stops the agent; entries left behind by
+//! crashed processes are dropped the next time the file is read.
+
+use fs2::FileExt;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::process;
+
+use crate::{free_port, runtime_dir, Ngrok, Probe, Result, ResultExt};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SharedState {
+    agent_pid: u32,
+    web_addr: SocketAddr,
+    /// One entry per acquisition, so a process may appear several times.
+    users: Vec<u32>,
+}
+
+fn state_path() -> PathBuf {
+    runtime_dir().join("shared-agent.json")
+}
+
+/// Hold the exclusive lock on the state file for the lifetime of the guard.
+fn lock() -> Result<File> {
+    let path = runtime_dir().join("shared-agent.lock");
+    fs::create_dir_all(runtime_dir()).chain_err(|| "could not create the runtime dir")?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .chain_err(|| format!("could not open {}", path.display()))?;
+    file.lock_exclusive()
+        .chain_err(|| format!("could not lock {}", path.display()))?;
+    Ok(file)
+}
+
+fn read_state() -> Option<SharedState> {
+    let text = fs::read_to_string(state_path()).ok()?;
+    match serde_json::from_str(&text) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            warn!("ignoring corrupt {}: {}", state_path().display(), err);
+            None
+        }
+    }
+}
+
+fn write_state(state: Option<&SharedState>) -> Result<()> {
+    let path = state_path();
+    match state {
+        Some(state) => {
+            let text = serde_json::to_string(state).chain_err(|| "could not serialize state")?;
+            fs::write(&path, text).chain_err(|| format!("could not write {}", path.display()))
+        }
+        None => match fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).chain_err(|| format!("could not remove {}", path.display()))
+            }
+            _ => Ok(()),
+        },
+    }
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(windows)]
+fn is_alive(pid: u32) -> bool {
+    process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+        .unwrap_or(false)
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) {
+    let _ = process::Command::new("kill").arg(pid.to_string()).status();
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) {
+    let _ = process::Command::new("taskkill")
+        .args(["/PID", &pid.to_string(), "/F"])
+        .status();
+}
+
+/// A registered use of the shared agent. Dereferences to a client bound to
+/// it; dropping it unregisters, and stops the agent if nobody else uses it.
+#[derive(Debug)]
+pub struct SharedAgent {
+    ngrok: Ngrok,
+    /// Set when this process spawned the agent.
+    child: Option<process::Child>,
+}
+
+impl SharedAgent {
+    /// Register as a user of the shared agent, spawning it if no live agent
+    /// is recorded.
+    pub fn acquire(template: &Ngrok) -> Result<Self> {
+        let _lock = lock()?;
+        let pid = process::id();
+        if let Some(mut state) = read_state() {
+            let ngrok = template.clone().with_web_addr(state.web_addr);
+            if is_alive(state.agent_pid) && ngrok.probe() == Probe::Agent {
+                state.users.retain(|&user| is_alive(user));
+                state.users.push(pid);
+                write_state(Some(&state))?;
+                debug!(
+                    "sharing ngrok agent {} on {}",
+                    state.agent_pid, state.web_addr
+                );
+                return Ok(SharedAgent { ngrok, child: None });
+            }
+            // the PID may have been reused: without an agent answering on
+            // the recorded port, nothing says that process is ours to kill
+            info!("discarding stale shared agent {}", state.agent_pid);
+            write_state(None)?;
+        }
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], free_port()?));
+        // its users come and go: the agent must not write to pipes read by
+        // the process that happened to spawn it
+        let started = template.spawn_logged(addr, Some(&runtime_dir().join("shared-agent.log")))?;
+        let child = started.child.ok_or("spawned agent has no process")?;
+        let state = SharedState {
+            agent_pid: child.id(),
+            web_addr: addr,
+            users: vec![pid],
+        };
+        write_state(Some(&state))?;
+        info!("shared ngrok agent {} on {}", state.agent_pid, addr);
+        Ok(SharedAgent {
+            ngrok: started.ngrok,
+            child: Some(child),
+        })
+    }
+
+    fn release(&mut self) -> Result<()> {
+        let _lock = lock()?;
+        let mut state = match read_state() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        if let Some(index) = state.users.iter().position(|&user| user == process::id()) {
+            state.users.remove(index);
+        }
+        state.users.retain(|&user| is_alive(user));
+        if !state.users.is_empty() {
+            return write_state(Some(&state));
+        }
+        info!("last user gone, stopping shared agent {}", state.agent_pid);
+        match self.child.take() {
+            Some(mut child) if child.id() == state.agent_pid => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            // spawned by another process: only stop it if it still answers
+            // as an agent where it was recorded, as the PID may be reused
+            _ => {
+                let ngrok = self.ngrok.clone().with_web_addr(state.web_addr);
+                if is_alive(state.agent_pid) && ngrok.probe() == Probe::Agent {
+                    terminate(state.agent_pid);
+                } else {
+                    warn!("not stopping {}: no agent there", state.agent_pid);
+                }
+            }
+        }
+        write_state(None)
+    }
+}
+
+impl Deref for SharedAgent {
+    type Target = Ngrok;
+
+    fn deref(&self) -> &Ngrok {
+        &self.ngrok
+    }
+}
+
+impl Drop for SharedAgent {
+    fn drop(&mut self) {
+        if let Err(err) = self.release() {
+            warn!("could not release the shared agent: {}", err);
+        }
+    }
+}
+
+impl Ngrok {
+    /// Opt-in shared mode: use the agent shared by all processes of this
+    /// user, see `SharedAgent`.
+    pub fn shared(&self) -> Result<SharedAgent> {
+        SharedAgent::acquire(self)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{is_alive, SharedState};
+    use std::process;
+
+    #[test]
+    fn liveness_and_state() {
+        assert!(is_alive(process::id()));
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(!is_alive(pid));
+
+        let state = SharedState {
+            agent_pid: 42,
+            web_addr: "127.0.0.1:4041".parse().unwrap(),
+            users: vec![1, 1, 7],
+        };
+        let text = serde_json::to_string(&state).unwrap();
+        assert_eq!(serde_json::from_str::<SharedState>(&text).unwrap(), state);
+    }
+}
@@ -0,0 +1,509 @@
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+use crate::{
+    api, Addr, Credentials, Error, ErrorKind, HeaderRules, HostHeader, IpNet, IpRestriction, Ngrok,
+    OAuth, Oidc, Result, TlsTermination, TrafficPolicy, Tunnel, UpstreamCheck,
+};
+
+/// The kind of tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Proto {
+    Http,
+    Tcp,
+    Tls,
+}
+
+impl Proto {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Proto::Http => "http",
+            Proto::Tcp => "tcp",
+            Proto::Tls => "tls",
+        }
+    }
+}
+
+impl fmt::Display for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Which public endpoints an http tunnel gets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BindTls {
+    /// Both an http and an https endpoint (the agent's default).
+    Both,
+    /// Only an https endpoint.
+    Https,
+    /// Only an http endpoint.
+    Http,
+}
+
+/// What `create_or_get` does when a tunnel with the same name already
+/// exists but was created with a different config.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    /// Delete the existing tunnel and create it again from the spec.
+    Recreate,
+    /// Leave the existing tunnel alone and return `ErrorKind::Conflict`.
+    Fail,
+}
+
+/// A tunnel to create, independently of the agent version; see `api` for
+/// how it is put on the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunnelSpec {
+    pub name: String,
+    pub proto: Proto,
+    pub addr: Addr,
+    pub bind_tls: Option<BindTls>,
+    pub inspect: Option<bool>,
+    /// Basic auth users; v2 agents take a single one.
+    pub basic_auth: Vec<Credentials>,
+    /// v3 agents only.
+    pub oauth: Option<OAuth>,
+    /// v3 agents only.
+    pub oidc: Option<Oidc>,
+    pub host_header: Option<HostHeader>,
+    /// v3 agents only.
+    pub request_header: HeaderRules,
+    /// v3 agents only.
+    pub response_header: HeaderRules,
+    /// v3 agents only.
+    pub ip_restriction: Option<IpRestriction>,
+    /// v3 agents only.
+    pub traffic_policy: Option<TrafficPolicy>,
+    /// tls tunnels only.
+    pub tls_termination: Option<TlsTermination>,
+    /// A reserved TCP address, e.g. `1.tcp.eu.ngrok.io:20123`; tcp tunnels
+    /// only.
+    pub remote_addr: Option<String>,
+    /// Checked before the tunnel is requested; never sent to the agent.
+    pub upstream_check: Option<UpstreamCheck>,
+}
+
+impl TunnelSpec {
+    pub fn new<N, A>(name: N, proto: Proto, addr: A) -> Self
+    where
+        N: Into<String>,
+        A: Into<Addr>,
+    {
+        TunnelSpec {
+            name: name.into(),
+            proto,
+            addr: addr.into(),
+            bind_tls: None,
+            inspect: None,
+            basic_auth: Vec::new(),
+            oauth: None,
+            oidc: None,
+            host_header: None,
+            request_header: HeaderRules::default(),
+            response_header: HeaderRules::default(),
+            ip_restriction: None,
+            traffic_policy: None,
+            tls_termination: None,
+            remote_addr: None,
+            upstream_check: None,
+        }
+    }
+
+    pub fn http<N, A>(name: N, addr: A) -> Self
+    where
+        N: Into<String>,
+        A: Into<Addr>,
+    {
+        Self::new(name, Proto::Http, addr)
+    }
+
+    pub fn tls<N, A>(name: N, addr: A) -> Self
+    where
+        N: Into<String>,
+        A: Into<Addr>,
+    {
+        Self::new(name, Proto::Tls, addr)
+    }
+
+    pub fn tcp<N, A>(name: N, addr: A) -> Self
+    where
+        N: Into<String>,
+        A: Into<Addr>,
+    {
+        Self::new(name, Proto::Tcp, addr)
+    }
+
+    pub fn bind_tls(mut self, bind_tls: BindTls) -> Self {
+        self.bind_tls = Some(bind_tls);
+        self
+    }
+
+    pub fn inspect(mut self, inspect: bool) -> Self {
+        self.inspect = Some(inspect);
+        self
+    }
+
+    /// Add a basic auth user.
+    pub fn basic_auth(mut self, credentials: Credentials) -> Self {
+        self.basic_auth.push(credentials);
+        self
+    }
+
+    pub fn oauth(mut self, oauth: OAuth) -> Self {
+        self.oauth = Some(oauth);
+        self
+    }
+
+    pub fn oidc(mut self, oidc: Oidc) -> Self {
+        self.oidc = Some(oidc);
+        self
+    }
+
+    pub fn host_header(mut self, host_header: HostHeader) -> Self {
+        self.host_header = Some(host_header);
+        self
+    }
+
+    /// Headers added to and removed from requests before they reach the
+    /// upstream.
+    pub fn request_header(mut self, rules: HeaderRules) -> Self {
+        self.request_header = rules;
+        self
+    }
+
+    /// Headers added to and removed from responses before they reach the
+    /// client.
+    pub fn response_header(mut self, rules: HeaderRules) -> Self {
+        self.response_header = rules;
+        self
+    }
+
+    /// Only let clients in `allow` (any, if empty) and not in `deny` in.
+    pub fn ip_restriction<A, D>(mut self, allow: A, deny: D) -> Result<Self>
+    where
+        A: IntoIterator<Item = IpNet>,
+        D: IntoIterator<Item = IpNet>,
+    {
+        self.ip_restriction = Some(IpRestriction::new(allow, deny)?);
+        Ok(self)
+    }
+
+    pub fn traffic_policy(mut self, policy: TrafficPolicy) -> Self {
+        self.traffic_policy = Some(policy);
+        self
+    }
+
+    pub fn tls_termination(mut self, termination: TlsTermination) -> Self {
+        self.tls_termination = Some(termination);
+        self
+    }
+
+    /// Use a reserved TCP address instead of a random one.
+    pub fn remote_addr<A: ToString>(mut self, remote_addr: A) -> Self {
+        self.remote_addr = Some(remote_addr.to_string());
+        self
+    }
+
+    /// Check the upstream with `check` before requesting the tunnel.
+    pub fn check_upstream(mut self, check: UpstreamCheck) -> Self {
+        self.upstream_check = Some(check);
+        self
+    }
+
+    /// Whether a request from `remote_addr` would get through the IP
+    /// restriction of this tunnel.
+    pub fn allows_remote(&self, remote_addr: &str) -> Result<bool> {
+        match &self.ip_restriction {
+            Some(restriction) => restriction.allows_remote(remote_addr),
+            None => Ok(true),
+        }
+    }
+
+    /// Whether the spec sets options the agent does not report back in
+    /// `TunnelConfig`, so an existing tunnel cannot be shown to have them.
+    fn has_unreported_options(&self) -> bool {
+        !self.basic_auth.is_empty()
+            || self.oauth.is_some()
+            || self.oidc.is_some()
+            || self.host_header.is_some()
+            || !self.request_header.is_empty()
+            || !self.response_header.is_empty()
+            || self.ip_restriction.is_some()
+            || self.traffic_policy.is_some()
+            || self.tls_termination.is_some()
+    }
+
+    /// Whether `tunnel`, as reported by the agent, was created from a spec
+    /// equivalent to this one. A spec with auth, header, IP or policy
+    /// options never matches: the agent does not report them, and handing
+    /// back a tunnel without them would expose the upstream unprotected.
+    /// `Ngrok::create_or_get` still recognizes the tunnels it created.
+    pub fn matches(&self, tunnel: &Tunnel) -> bool {
+        !self.has_unreported_options() && self.matches_reported(tunnel)
+    }
+
+    /// Whether the options the agent reports back match.
+    fn matches_reported(&self, tunnel: &Tunnel) -> bool {
+        let proto_ok = match (self.proto, self.bind_tls) {
+            (Proto::Http, Some(BindTls::Http)) => tunnel.proto == "http",
+            (Proto::Http, Some(BindTls::Https)) => tunnel.proto == "https",
+            // with both, the tunnel carrying the requested name is the https one
+            (Proto::Http, _) => tunnel.proto == "http" || tunnel.proto == "https",
+            (proto, _) => tunnel.proto == proto.as_str(),
+        };
+        let addr_ok = tunnel.config.addr.normalize() == self.addr.normalize();
+        let inspect_ok = self
+            .inspect
+            .is_none_or(|inspect| inspect == tunnel.config.inspect);
+        // a reserved address shows as the public URL
+        let remote_ok = self.remote_addr.as_ref().is_none_or(|remote_addr| {
+            tunnel.public_url.trim_start_matches("tcp://") == remote_addr.as_str()
+        });
+        proto_ok && addr_ok && inspect_ok && remote_ok
+    }
+}
+
+pub(crate) fn tunnel_path(name: &str) -> String {
+    format!("api/tunnels/{}", name)
+}
+
+pub(crate) fn is_not_found(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::Server(404, _))
+}
+
+impl Ngrok {
+    pub fn create_tunnel(&self, spec: &TunnelSpec) -> Result<Tunnel> {
+        let version = self.api_version();
+        let data = api::tunnel_request(version, spec)?;
+        if let Some(check) = &spec.upstream_check {
+            check.run(&spec.addr)?;
+        }
+        let value = self.post::<Value>("api/tunnels", data)?;
+        let tunnel = api::parse_tunnel(version, value)?;
+        self.created
+            .lock()
+            .unwrap()
+            .insert(spec.name.clone(), (spec.clone(), tunnel.public_url.clone()));
+        Ok(tunnel)
+    }
+
+    /// Whether `tunnel` is the one this client created from `spec`, or
+    /// otherwise shown to match it.
+    fn is_from(&self, spec: &TunnelSpec, tunnel: &Tunnel) -> bool {
+        if spec.matches(tunnel) {
+            return true;
+        }
+        let created = self.created.lock().unwrap();
+        created
+            .get(&spec.name)
+            .is_some_and(|(created, public_url)| {
+                created == spec && *public_url == tunnel.public_url && spec.matches_reported(tunnel)
+            })
+    }
+
+    pub fn tunnel(&self, name: &str) -> Result<Tunnel> {
+        let value = self.get::<Value>(&tunnel_path(name))?;
+        api::parse_tunnel(self.api_version(), value)
+    }
+
+    /// Create the tunnel described by `spec`, or return the existing tunnel
+    /// of the same name if its config matches. Safe to call again after a
+    /// failed or interrupted attempt.
+    ///
+    /// The agent does not report auth, header, IP or policy options, so a
+    /// tunnel with them only matches if this client created it from the
+    /// same spec; otherwise `ConflictPolicy::Fail` returns
+    /// `ErrorKind::Unverifiable`.
+    pub fn create_or_get(&self, spec: &TunnelSpec, policy: ConflictPolicy) -> Result<Tunnel> {
+        match self.tunnel(&spec.name) {
+            Ok(tunnel) if self.is_from(spec, &tunnel) => {
+                debug!("tunnel {} already exists", spec.name);
+                Ok(tunnel)
+            }
+            Ok(tunnel) => match policy {
+                ConflictPolicy::Fail if spec.matches_reported(&tunnel) => {
+                    Err(ErrorKind::Unverifiable(spec.name.clone()).into())
+                }
+                ConflictPolicy::Fail => Err(ErrorKind::Conflict(
+                    spec.name.clone(),
+                    format!("{} {:?}", tunnel.proto, tunnel.config),
+                    format!("{:?}", spec),
+                )
+                .into()),
+                ConflictPolicy::Recreate => {
+                    let why = if spec.matches_reported(&tunnel) {
+                        "its protections cannot be verified"
+                    } else {
+                        "config changed"
+                    };
+                    info!("recreating tunnel {}: {}", spec.name, why);
+                    self.delete(&tunnel_path(&spec.name))?;
+                    // bind_tls "both" leaves a companion http tunnel behind
+                    let companion = tunnel_path(&format!("{} (http)", spec.name));
+                    if let Err(err) = self.delete(&companion) {
+                        if !is_not_found(&err) {
+                            return Err(err);
+                        }
+                    }
+                    self.create_tunnel(spec)
+                }
+            },
+            Err(ref err) if is_not_found(err) => match self.create_tunnel(spec) {
+                Ok(tunnel) => Ok(tunnel),
+                Err(err) => {
+                    // somebody else may have created it in the meantime
+                    warn!("could not create tunnel {}: {}", spec.name, err);
+                    match self.tunnel(&spec.name) {
+                        Ok(tunnel) if self.is_from(spec, &tunnel) => Ok(tunnel),
+                        _ => Err(err),
+                    }
+                }
+            },
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BindTls, ConflictPolicy, TunnelSpec};
+    use crate::transport::{Method, Request, Response, Transport};
+    use crate::{tunnel_fixture, Credentials, ErrorKind, Ngrok, Result, Tunnel};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    /// A v3 agent keeping the tunnels created through it.
+    #[derive(Debug, Default)]
+    struct Agent {
+        tunnels: Mutex<Vec<Value>>,
+        creates: Mutex<usize>,
+    }
+
+    impl Transport for Arc<Agent> {
+        fn send(&self, request: Request) -> Result<Response> {
+            let mut tunnels = self.tunnels.lock().unwrap();
+            match (request.method, request.url.path()) {
+                (Method::Get, "/api/status") => {
+                    Ok(Response::new(200, "OK", r#"{"version": "3.5.0"}"#))
+                }
+                (Method::Post, "/api/tunnels") => {
+                    let body: Value = serde_json::from_slice(&request.body.unwrap()).unwrap();
+                    let mut creates = self.creates.lock().unwrap();
+                    *creates += 1;
+                    let public_url = format!("https://{}.ngrok.app", creates);
+                    let mut tunnel = tunnel_fixture(
+                        body["name"].as_str().unwrap(),
+                        "https",
+                        &public_url,
+                        "http://localhost:8069",
+                    );
+                    tunnel["ID"] = json!(creates.to_string());
+                    tunnels.push(tunnel.clone());
+                    Ok(Response::new(201, "Created", tunnel.to_string()))
+                }
+                (Method::Get, path) => match tunnels.iter().find(|t| t["uri"] == path) {
+                    Some(tunnel) => Ok(Response::new(200, "OK", tunnel.to_string())),
+                    None => Ok(Response::new(404, "Not Found", "{}")),
+                },
+                (Method::Delete, path) => {
+                    tunnels.retain(|t| t["uri"] != path);
+                    Ok(Response::new(204, "No Content", ""))
+                }
+                _ => Ok(Response::new(404, "Not Found", "{}")),
+            }
+        }
+    }
+
+    fn tunnel(proto: &str, addr: &str, inspect: bool) -> Tunnel {
+        let public_url = match proto {
+            "tcp" => "tcp://127.0.0.1:12345",
+            _ => "https://abcd.ngrok.io",
+        };
+        let mut tunnel = tunnel_fixture("erp", proto, public_url, addr);
+        tunnel["config"]["inspect"] = json!(inspect);
+        serde_json::from_value(tunnel).unwrap()
+    }
+
+    #[test]
+    fn spec_matches_reported_tunnel() {
+        let spec = TunnelSpec::http("erp", 8069).inspect(true);
+        assert!(spec.matches(&tunnel("https", "http://localhost:8069", true)));
+        assert!(!spec.matches(&tunnel("https", "http://localhost:8070", true)));
+        assert!(!spec.matches(&tunnel("https", "http://localhost:8069", false)));
+        assert!(!spec.matches(&tunnel("tcp", "localhost:8069", true)));
+
+        let plain = TunnelSpec::http("erp", 8069).bind_tls(BindTls::Http);
+        assert!(plain.matches(&tunnel("http", "http://localhost:8069", false)));
+        assert!(!plain.matches(&tunnel("https", "http://localhost:8069", false)));
+        let secure = TunnelSpec::http("erp", 8069).bind_tls(BindTls::Https);
+        assert!(!secure.matches(&tunnel("http", "http://localhost:8069", false)));
+    }
+
+    #[test]
+    fn protected_specs_do_not_match_open_tunnels() {
+        let open = tunnel("https", "http://localhost:8069", false);
+        assert!(TunnelSpec::http("erp", 8069).matches(&open));
+        let credentials = Credentials::new("admin", "s3cret-pass").unwrap();
+        assert!(!TunnelSpec::http("erp", 8069)
+            .basic_auth(credentials)
+            .matches(&open));
+        let restricted = TunnelSpec::http("erp", 8069)
+            .ip_restriction(vec!["10.0.0.0/8".parse().unwrap()], vec![])
+            .unwrap();
+        assert!(!restricted.matches(&open));
+    }
+
+    #[test]
+    fn tcp_tunnels() {
+        let spec = TunnelSpec::tcp("ssh", 22).remote_addr("127.0.0.1:12345");
+        let ssh = tunnel("tcp", "tcp://localhost:22", false);
+        assert!(spec.matches(&ssh));
+        assert!(!TunnelSpec::tcp("ssh", 22)
+            .remote_addr("1.tcp.eu.ngrok.io:20123")
+            .matches(&ssh));
+        assert_eq!(ssh.config.local_target().to_string(), "localhost:22");
+        assert_eq!(
+            ssh.socket_addr().unwrap(),
+            "127.0.0.1:12345".parse().unwrap()
+        );
+        let err = crate::api::tunnel_request(
+            crate::AgentVersion::V3,
+            &TunnelSpec::http("erp", 8069).remote_addr("1.tcp.eu.ngrok.io:20123"),
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind(), crate::ErrorKind::InvalidSpec(_)));
+    }
+
+    #[test]
+    fn retries_find_own_protected_tunnels() {
+        let agent = Arc::new(Agent::default());
+        let ngrok = Ngrok::with_transport(Arc::clone(&agent));
+        let spec = TunnelSpec::http("erp", 8069)
+            .basic_auth(Credentials::new("admin", "s3cret-pass").unwrap());
+        let created = ngrok.create_or_get(&spec, ConflictPolicy::Fail).unwrap();
+        for policy in &[ConflictPolicy::Fail, ConflictPolicy::Recreate] {
+            let again = ngrok.clone().create_or_get(&spec, *policy).unwrap();
+            assert_eq!(again.public_url, created.public_url);
+        }
+        assert_eq!(*agent.creates.lock().unwrap(), 1);
+
+        // another client cannot tell whether the tunnel has basic auth
+        let other = Ngrok::with_transport(Arc::clone(&agent));
+        let err = other
+            .create_or_get(&spec, ConflictPolicy::Fail)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Unverifiable(name) if name == "erp"));
+        let recreated = other
+            .create_or_get(&spec, ConflictPolicy::Recreate)
+            .unwrap();
+        assert_ne!(recreated.public_url, created.public_url);
+
+        // nor can the first one once the spec changed
+        let changed = spec.clone().inspect(false);
+        assert!(ngrok.create_or_get(&changed, ConflictPolicy::Fail).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! Attaching to an agent already listening, or spawning our own.
+
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::transport::Method;
+use crate::{
+    spawn_agent, startup_error, AgentConfig, AgentVersion, Error, ErrorKind, Ngrok, Result,
+    ResultExt, Tunnels,
+};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const STARTUP_POLL: Duration = Duration::from_millis(100);
+
+/// Whether `start_with` may use an agent that is already running, spawn
+/// its own, or both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StartPolicy {
+    /// Only use an agent already answering on the web address.
+    AttachOnly,
+    /// Always spawn an agent, on a free port if the web address is taken.
+    SpawnOnly,
+    /// Use the agent on the web address, or spawn one: there if nothing
+    /// listens, on a free port if something that is not ngrok does.
+    #[default]
+    AttachOrSpawn,
+}
+
+/// What answers on the web address of a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    Nothing,
+    Agent,
+    /// Something answers, but not the way an ngrok agent does.
+    Foreign(String),
+}
+
+#[derive(Debug)]
+pub struct Started {
+    /// Client bound to the agent in use, which may listen on another port
+    /// than the one asked for.
+    pub ngrok: Ngrok,
+    pub tunnels: Tunnels,
+    /// The agent process, when we spawned it.
+    pub child: Option<process::Child>,
+}
+
+/// Directory for the config and lock files of the agents we spawn.
+pub fn runtime_dir() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("ngrok2")
+}
+
+/// A local port nobody listens on right now.
+pub fn free_port() -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0").chain_err(|| "no free local port")?;
+    let addr = listener.local_addr().chain_err(|| "no free local port")?;
+    Ok(addr.port())
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+/// Where an agent of `version` reads its config by default.
+pub fn default_config_path(version: AgentVersion) -> Option<PathBuf> {
+    match version {
+        AgentVersion::V2 => home_dir().map(|home| home.join(".ngrok2").join("ngrok.yml")),
+        AgentVersion::V3 => {
+            let dir = if cfg!(target_os = "macos") {
+                home_dir().map(|home| home.join("Library").join("Application Support"))
+            } else if cfg!(windows) {
+                env::var_os("LOCALAPPDATA").map(PathBuf::from)
+            } else {
+                env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| home_dir().map(|home| home.join(".config")))
+            };
+            dir.map(|dir| dir.join("ngrok").join("ngrok.yml"))
+        }
+    }
+}
+
+/// The config file `spawn_on` writes for the agent on `web_addr`.
+pub fn agent_config_path(web_addr: SocketAddr) -> PathBuf {
+    runtime_dir().join(format!("agent-{}-{}.yml", process::id(), web_addr.port()))
+}
+
+/// Write `config` for an agent of `version` to `path`.
+pub fn write_config(config: &AgentConfig, version: AgentVersion, path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).chain_err(|| format!("could not create {}", dir.display()))?;
+    }
+    fs::write(path, config.to_yaml(version)?)
+        .chain_err(|| format!("could not write {}", path.display()))
+}
+
+impl Ngrok {
+    pub(crate) fn web_addr(&self) -> Result<SocketAddr> {
+        self.base_url
+            .socket_addrs(|| Some(80))
+            .ok()
+            .and_then(|addrs| addrs.into_iter().next())
+            .ok_or_else(|| Error::from(format!("bad agent address {}", self.base_url)))
+    }
+
+    /// Check whether an ngrok agent answers on our web address, from the
+    /// shape of `api/tunnels` and, when served, `api/status`.
+    pub fn probe(&self) -> Probe {
+        let resp = match self.request(Method::Get, "api/tunnels", None) {
+            Ok(resp) => resp,
+            Err(err) => {
+                debug!("nothing on {}: {}", self.base_url, err);
+                return Probe::Nothing;
+            }
+        };
+        if resp.status != 200 {
+            return Probe::Foreign(format!(
+                "api/tunnels answered {} {}",
+                resp.status, resp.status_text
+            ));
+        }
+        let value = match resp.json::<Value>() {
+            Ok(value) => value,
+            Err(err) => return Probe::Foreign(format!("api/tunnels: {}", err)),
+        };
+        if value.get("uri").and_then(Value::as_str) != Some("/api/tunnels")
+            || serde_json::from_value::<Tunnels>(value).is_err()
+        {
+            return Probe::Foreign("api/tunnels is not a tunnel list".to_owned());
+        }
+        match self.request(Method::Get, "api/status", None) {
+            Ok(resp) if resp.status == 200 => match resp.json::<Value>() {
+                Ok(Value::Object(_)) => Probe::Agent,
+                _ => Probe::Foreign("api/status is not an agent status".to_owned()),
+            },
+            // not every agent serves it
+            Ok(resp) if resp.status == 404 => Probe::Agent,
+            Ok(resp) => Probe::Foreign(format!(
+                "api/status answered {} {}",
+                resp.status, resp.status_text
+            )),
+            // something answered api/tunnels just before
+            Err(err) => Probe::Foreign(format!("api/status failed: {}", err)),
+        }
+    }
+
+    /// Get an agent to talk to according to `policy`.
+    pub fn start_with(&self, policy: StartPolicy) -> Result<Started> {
+        let probe = self.probe();
+        debug!("{}: {:?}", self.base_url, probe);
+        match (policy, probe) {
+            (StartPolicy::AttachOnly, Probe::Agent)
+            | (StartPolicy::AttachOrSpawn, Probe::Agent) => {
+                info!("attaching to the ngrok agent on {}", self.base_url);
+                Ok(Started {
+                    ngrok: self.clone(),
+                    tunnels: self.get::<Tunnels>("api/tunnels")?,
+                    child: None,
+                })
+            }
+            (StartPolicy::AttachOnly, Probe::Nothing) => {
+                Err(ErrorKind::AgentUnavailable(self.base_url.to_string()).into())
+            }
+            (StartPolicy::AttachOnly, Probe::Foreign(reason)) => {
+                Err(ErrorKind::NotAnAgent(self.base_url.to_string(), reason).into())
+            }
+            (_, Probe::Nothing) => self.spawn_on(self.web_addr()?),
+            (_, probe) => {
+                if let Probe::Foreign(reason) = probe {
+                    warn!("{} is not an ngrok agent: {}", self.base_url, reason);
+                }
+                let addr = SocketAddr::from(([127, 0, 0, 1], free_port()?));
+                self.spawn_on(addr)
+            }
+        }
+    }
+
+    /// Spawn an agent whose web interface listens on `web_addr`, and wait
+    /// for it to answer.
+    pub fn spawn_on(&self, web_addr: SocketAddr) -> Result<Started> {
+        self.spawn_logged(web_addr, None)
+    }
+
+    /// `spawn_on`, with the agent output written to `log` instead of piped
+    /// to this process.
+    pub(crate) fn spawn_logged(&self, web_addr: SocketAddr, log: Option<&Path>) -> Result<Started> {
+        let path = self.executable()?;
+        let version = Self::binary_version(&path)
+            .and_then(|version| AgentVersion::from_version(&version))
+            .unwrap_or_else(|err| {
+                warn!("{}, assuming a v2 agent", err);
+                AgentVersion::V2
+            });
+        let config = AgentConfig {
+            web_addr: Some(web_addr.to_string()),
+            ..AgentConfig::default()
+        };
+        let config_path = agent_config_path(web_addr);
+        write_config(&config, version, &config_path)?;
+        let started = self.run_agent(&path, version, &config_path, web_addr, log);
+        // read by the agent on startup only
+        if let Err(err) = fs::remove_file(&config_path) {
+            debug!("could not remove {}: {}", config_path.display(), err);
+        }
+        started
+    }
+
+    /// Run the agent at `path` with `config_path` and wait for it to answer
+    /// on `web_addr`.
+    fn run_agent(
+        &self,
+        path: &Path,
+        version: AgentVersion,
+        config_path: &Path,
+        web_addr: SocketAddr,
+        log: Option<&Path>,
+    ) -> Result<Started> {
+        // keep the user's config (authtoken, region...) and override web_addr
+        let mut args: Vec<&OsStr> = Vec::new();
+        let default_config = default_config_path(version).filter(|path| path.is_file());
+        if let Some(default_config) = &default_config {
+            args.push(OsStr::new("--config"));
+            args.push(default_config.as_os_str());
+        }
+        args.push(OsStr::new("--config"));
+        args.push(config_path.as_os_str());
+        let (mut child, output) = spawn_agent(path, &args, log)?;
+
+        let ngrok = self.clone().with_web_addr(web_addr);
+        let _ = ngrok.agent_version.set(version);
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            if ngrok.probe() == Probe::Agent {
+                if let Ok(tunnels) = ngrok.get::<Tunnels>("api/tunnels") {
+                    return Ok(Started {
+                        ngrok,
+                        tunnels,
+                        child: Some(child),
+                    });
+                }
+            }
+            if let Ok(Some(status)) = child.try_wait() {
+                return Err(startup_error(&output, status));
+            }
+            if Instant::now() > deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("ngrok did not come up on {}", web_addr).into());
+            }
+            thread::sleep(STARTUP_POLL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{free_port, Probe, StartPolicy};
+    use crate::transport::{Request, Response, Transport};
+    use crate::{ErrorKind, Ngrok, Result};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    #[test]
+    fn probe_foreign_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(1) {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf);
+                let body = "<html>dev server</html>";
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        let ngrok = Ngrok::new().with_web_addr(addr);
+        assert!(matches!(ngrok.probe(), Probe::Foreign(_)));
+    }
+
+    #[derive(Debug)]
+    struct StatusFails;
+
+    impl Transport for StatusFails {
+        fn send(&self, request: Request) -> Result<Response> {
+            match request.url.path() {
+                "/api/tunnels" => Ok(Response::new(
+                    200,
+                    "OK",
+                    r#"{"tunnels": [], "uri": "/api/tunnels"}"#,
+                )),
+                _ => Err("connection reset".into()),
+            }
+        }
+    }
+
+    #[test]
+    fn probe_status_failure() {
+        let ngrok = Ngrok::with_transport(StatusFails);
+        assert!(matches!(ngrok.probe(), Probe::Foreign(_)));
+        let err = ngrok.start().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::NotAnAgent(_, _)));
+    }
+
+    #[test]
+    fn attach_only_without_agent() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], free_port().unwrap()));
+        let ngrok = Ngrok::new().with_web_addr(addr);
+        assert_eq!(ngrok.probe(), Probe::Nothing);
+        let err = ngrok.start_with(StartPolicy::AttachOnly).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::AgentUnavailable(_)));
+    }
+}
@@ -0,0 +1,292 @@
+//! Why a spawned agent did not come up.
+//!
+//! The agent's stdout and stderr are drained into an `AgentOutput` from the
+//! moment it is spawned. When it exits early, that output and the exit code
+//! are turned into a `StartupError` by `classify`, from the `ERR_NGROK_*`
+//! code the agent prints or, failing that, from the wording of its message.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How much of the agent output is kept.
+const OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// What the agent said before exiting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentFailure {
+    /// The `ERR_NGROK_*` code, when printed.
+    pub code: Option<String>,
+    pub message: String,
+    /// The documentation link printed along with the error.
+    pub doc_url: Option<String>,
+    pub exit_code: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartupError {
+    /// ERR_NGROK_108: the account already runs as many agents as allowed.
+    SessionLimit(AgentFailure),
+    /// ERR_NGROK_4018: no authtoken configured.
+    AuthtokenMissing(AgentFailure),
+    /// ERR_NGROK_105, ERR_NGROK_107: the authtoken is malformed or revoked.
+    AuthtokenInvalid(AgentFailure),
+    /// ERR_NGROK_103: the account is suspended.
+    AccountSuspended(AgentFailure),
+    Other(AgentFailure),
+}
+
+impl StartupError {
+    pub fn failure(&self) -> &AgentFailure {
+        match self {
+            StartupError::SessionLimit(failure)
+            | StartupError::AuthtokenMissing(failure)
+            | StartupError::AuthtokenInvalid(failure)
+            | StartupError::AccountSuspended(failure)
+            | StartupError::Other(failure) => failure,
+        }
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            StartupError::SessionLimit(_) => "agent session limit reached",
+            StartupError::AuthtokenMissing(_) => "missing authtoken",
+            StartupError::AuthtokenInvalid(_) => "invalid authtoken",
+            StartupError::AccountSuspended(_) => "account suspended",
+            StartupError::Other(_) => "agent exited",
+        };
+        let failure = self.failure();
+        write!(f, "{}: {}", reason, failure.message)?;
+        if let Some(code) = &failure.code {
+            write!(f, " ({})", code)?;
+        }
+        if let Some(url) = &failure.doc_url {
+            write!(f, ", see {}", url)?;
+        }
+        Ok(())
+    }
+}
+
+/// Output of a spawned agent, read in the background so the agent never
+/// blocks on a full pipe, or written to a log file by the agent itself.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AgentOutput {
+    text: Arc<Mutex<String>>,
+    readers: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
+    log: Option<PathBuf>,
+}
+
+impl AgentOutput {
+    /// Start draining the piped stdout and stderr of `child`.
+    pub(crate) fn capture(child: &mut Child) -> Self {
+        let output = AgentOutput::default();
+        let mut readers = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(output.drain(stdout));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(output.drain(stderr));
+        }
+        *output.readers.lock().unwrap() = readers;
+        output
+    }
+
+    /// Output the agent writes to the file at `path`.
+    pub(crate) fn logged_to(path: &Path) -> Self {
+        AgentOutput {
+            log: Some(path.to_owned()),
+            ..AgentOutput::default()
+        }
+    }
+
+    fn drain<R: Read + Send + 'static>(&self, mut pipe: R) -> thread::JoinHandle<()> {
+        let text = Arc::clone(&self.text);
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok(n) = pipe.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                let mut text = text.lock().unwrap();
+                let keep = n.min(OUTPUT_LIMIT.saturating_sub(text.len()));
+                text.push_str(&String::from_utf8_lossy(&buf[..keep]));
+            }
+        })
+    }
+
+    /// What was read so far.
+    pub fn text(&self) -> String {
+        match &self.log {
+            Some(path) => {
+                let mut text = String::new();
+                if let Ok(file) = File::open(path) {
+                    let _ = file.take(OUTPUT_LIMIT as u64).read_to_string(&mut text);
+                }
+                text
+            }
+            None => self.text.lock().unwrap().clone(),
+        }
+    }
+
+    /// Everything the agent printed. Only call once it exited, as this
+    /// reads its pipes to the end.
+    pub(crate) fn finish(&self) -> String {
+        for reader in self.readers.lock().unwrap().drain(..) {
+            let _ = reader.join();
+        }
+        self.text()
+    }
+}
+
+/// Value of `key=` in a logfmt line, unquoted.
+fn logfmt_value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!(" {}=", key))? + key.len() + 2;
+    let rest = &line[start..];
+    match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next(),
+        None => rest.split_whitespace().next(),
+    }
+}
+
+fn error_code(text: &str) -> Option<String> {
+    let start = text.find("ERR_NGROK_")?;
+    let code: String = text[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect();
+    Some(code)
+}
+
+fn doc_url(text: &str) -> Option<String> {
+    let urls: Vec<&str> = text
+        .split_whitespace()
+        .filter(|word| word.starts_with("https://") || word.starts_with("http://"))
+        .map(|word| word.trim_end_matches(['.', ',', ')', '"']))
+        .collect();
+    urls.iter()
+        .find(|url| url.to_ascii_lowercase().contains("err_ngrok"))
+        .or_else(|| urls.iter().find(|url| url.contains("/docs")))
+        .or_else(|| urls.first())
+        .map(|url| url.to_string())
+}
+
+/// The agent's own description of the error.
+fn message(text: &str) -> String {
+    let lines: Vec<&str> = text
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("ERROR:"))
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("ERR_NGROK_"))
+        .collect();
+    if let Some(first) = lines.first() {
+        return first.to_string();
+    }
+    let logged = text
+        .lines()
+        .filter(|line| line.contains("lvl=crit") || line.contains("lvl=eror"))
+        .find_map(|line| logfmt_value(line, "err").or_else(|| logfmt_value(line, "msg")));
+    if let Some(logged) = logged {
+        return logged.to_owned();
+    }
+    text.lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .unwrap_or("no output")
+        .to_owned()
+}
+
+/// Turn what an agent printed before exiting with `status` into an error.
+pub(crate) fn classify(output: &str, status: Option<ExitStatus>) -> StartupError {
+    let failure = AgentFailure {
+        code: error_code(output),
+        message: message(output),
+        doc_url: doc_url(output),
+        exit_code: status.and_then(|status| status.code()),
+    };
+    let text = output.to_ascii_lowercase();
+    match failure.code.as_deref() {
+        Some("ERR_NGROK_108") => StartupError::SessionLimit(failure),
+        Some("ERR_NGROK_4018") => StartupError::AuthtokenMissing(failure),
+        Some("ERR_NGROK_105") | Some("ERR_NGROK_107") => StartupError::AuthtokenInvalid(failure),
+        Some("ERR_NGROK_103") => StartupError::AccountSuspended(failure),
+        Some(_) => StartupError::Other(failure),
+        None if text.contains("suspended") => StartupError::AccountSuspended(failure),
+        None if text.contains("simultaneous") && text.contains("session") => {
+            StartupError::SessionLimit(failure)
+        }
+        None if text.contains("authtoken") && text.contains("requires") => {
+            StartupError::AuthtokenMissing(failure)
+        }
+        None if text.contains("authtoken") && text.contains("invalid") => {
+            StartupError::AuthtokenInvalid(failure)
+        }
+        None => StartupError::Other(failure),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, AgentOutput, StartupError};
+    use std::{env, fs, process};
+
+    #[test]
+    fn session_limit() {
+        let output = "\
+ERROR:  authentication failed: Your account is limited to 1 simultaneous ngrok agent session.
+ERROR:  You can run multiple simultaneous tunnels from a single agent session.
+ERROR:
+ERROR:  ERR_NGROK_108
+ERROR:  https://ngrok.com/docs/errors/err_ngrok_108
+";
+        match classify(output, None) {
+            StartupError::SessionLimit(failure) => {
+                assert_eq!(failure.code.as_deref(), Some("ERR_NGROK_108"));
+                assert_eq!(
+                    failure.message,
+                    "authentication failed: Your account is limited to 1 simultaneous ngrok agent session."
+                );
+                assert_eq!(
+                    failure.doc_url.as_deref(),
+                    Some("https://ngrok.com/docs/errors/err_ngrok_108")
+                );
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn logfmt_and_unknown_output() {
+        let output = r#"t=2023-01-01T00:00:00+0000 lvl=crit msg="command failed" err="The authtoken you specified is properly formed, but it is invalid.""#;
+        match classify(output, None) {
+            StartupError::AuthtokenInvalid(failure) => assert_eq!(
+                failure.message,
+                "The authtoken you specified is properly formed, but it is invalid."
+            ),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        let err = classify("flag provided but not defined: -none\n", None);
+        assert_eq!(
+            err.failure().message,
+            "flag provided but not defined: -none"
+        );
+        assert!(matches!(err, StartupError::Other(_)));
+    }
+
+    #[test]
+    fn logged_output() {
+        let log = env::temp_dir().join(format!("ngrok-output-{}.log", process::id()));
+        fs::write(&log, "ERROR:  authtoken missing\nERR_NGROK_4018\n").unwrap();
+        let output = AgentOutput::logged_to(&log);
+        assert!(matches!(
+            classify(&output.finish(), None),
+            StartupError::AuthtokenMissing(_)
+        ));
+        fs::remove_file(&log).unwrap();
+    }
+}
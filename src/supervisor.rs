@@ -0,0 +1,336 @@
+//! Keep an agent and its tunnels alive.
+//!
+//! `Supervisor` watches the agent process from a background thread. When
+//! the process exits or its API stops answering, the agent is restarted on
+//! the same web_addr, with exponential backoff, and the known tunnels are
+//! created again. Tunnels lost while the agent kept running (a dropped
+//! session) are recreated too, backing off while that keeps failing. Each
+//! time, the callback gets the tunnels with their new public URLs.
+
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{ConflictPolicy, Ngrok, Result, Tunnel, TunnelSpec, Tunnels};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Failed API checks in a row before the agent is deemed unresponsive.
+const MAX_FAILED_CHECKS: u32 = 3;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Delay before restart attempt number `attempt` (starting at 0).
+fn backoff(attempt: u32) -> Duration {
+    MIN_BACKOFF
+        .checked_mul(1 << attempt.min(16))
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+type Callback = Box<dyn FnMut(&[Tunnel]) + Send>;
+
+struct Watch {
+    ngrok: Ngrok,
+    child: Option<process::Child>,
+    specs: Arc<Mutex<Vec<TunnelSpec>>>,
+    on_tunnels: Callback,
+    /// Failed restores in a row and when to try again, by tunnel name.
+    failures: HashMap<String, (u32, Instant)>,
+}
+
+impl Watch {
+    /// Create the known tunnels missing from `current` and report them.
+    fn restore(&mut self, current: &Tunnels) {
+        let names: Vec<String> = self
+            .specs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|spec| spec.name.clone())
+            .collect();
+        let now = Instant::now();
+        let mut restored = Vec::new();
+        for name in names {
+            if current.tunnels.iter().any(|t| t.name == name) {
+                self.failures.remove(&name);
+                continue;
+            }
+            if let Some(&(_, retry_at)) = self.failures.get(&name) {
+                if now < retry_at {
+                    continue;
+                }
+            }
+            // hold the lock while creating, so remove_tunnel cannot delete
+            // the tunnel in between and have it come back
+            let specs = self.specs.lock().unwrap();
+            let spec = match specs.iter().find(|spec| spec.name == name) {
+                Some(spec) => spec,
+                None => continue,
+            };
+            match self.ngrok.create_or_get(spec, ConflictPolicy::Recreate) {
+                Ok(tunnel) => {
+                    info!("restored tunnel {}: {}", tunnel.name, tunnel.public_url);
+                    self.failures.remove(&name);
+                    restored.push(tunnel);
+                }
+                Err(err) => {
+                    let attempt = self.failures.get(&name).map_or(0, |&(attempt, _)| attempt);
+                    let delay = backoff(attempt);
+                    error!(
+                        "could not restore tunnel {}: {}, retrying in {:?}",
+                        name, err, delay
+                    );
+                    self.failures.insert(name, (attempt + 1, now + delay));
+                }
+            }
+        }
+        if !restored.is_empty() {
+            (self.on_tunnels)(&restored);
+        }
+    }
+
+    fn restart(&mut self, stop: &mpsc::Receiver<()>) -> bool {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let web_addr = match self.ngrok.web_addr() {
+            Ok(web_addr) => web_addr,
+            Err(err) => {
+                error!("cannot restart ngrok: {}", err);
+                return false;
+            }
+        };
+        let mut attempt = 0;
+        loop {
+            match self.ngrok.spawn_on(web_addr) {
+                Ok(started) => {
+                    info!("ngrok restarted on {}", web_addr);
+                    self.child = started.child;
+                    self.failures.clear();
+                    self.restore(&started.tunnels);
+                    return true;
+                }
+                Err(err) => {
+                    let delay = backoff(attempt);
+                    warn!("ngrok restart failed ({}), retrying in {:?}", err, delay);
+                    attempt += 1;
+                    if !matches!(stop.recv_timeout(delay), Err(RecvTimeoutError::Timeout)) {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    fn run(mut self, stop: mpsc::Receiver<()>) {
+        let mut failed_checks = 0;
+        loop {
+            if !matches!(
+                stop.recv_timeout(CHECK_INTERVAL),
+                Err(RecvTimeoutError::Timeout)
+            ) {
+                break;
+            }
+            let exited = match self.child.as_mut().map(|child| child.try_wait()) {
+                Some(Ok(Some(status))) => {
+                    warn!("ngrok exited: {}", status);
+                    true
+                }
+                _ => false,
+            };
+            if !exited {
+                match self.ngrok.get::<Tunnels>("api/tunnels") {
+                    Ok(tunnels) => {
+                        failed_checks = 0;
+                        self.restore(&tunnels);
+                        continue;
+                    }
+                    Err(err) => {
+                        failed_checks += 1;
+                        debug!("ngrok check {} failed: {}", failed_checks, err);
+                        if failed_checks < MAX_FAILED_CHECKS {
+                            continue;
+                        }
+                        warn!("ngrok API unresponsive");
+                    }
+                }
+            }
+            failed_checks = 0;
+            if !self.restart(&stop) {
+                break;
+            }
+        }
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Supervises an agent until dropped, then stops it.
+pub struct Supervisor {
+    ngrok: Ngrok,
+    specs: Arc<Mutex<Vec<TunnelSpec>>>,
+    stop: Option<Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("ngrok", &self.ngrok)
+            .field("specs", &self.specs)
+            .finish()
+    }
+}
+
+impl Supervisor {
+    /// Take over `child`, the agent `ngrok` talks to (as returned by
+    /// `start_server`), create `specs` and keep them alive. `on_tunnels` is
+    /// called with every tunnel (re)created, starting with `specs`.
+    ///
+    /// Fails with the first tunnel that cannot be created; `child` is
+    /// stopped then.
+    pub fn start<F>(
+        ngrok: &Ngrok,
+        mut child: process::Child,
+        specs: Vec<TunnelSpec>,
+        mut on_tunnels: F,
+    ) -> Result<Self>
+    where
+        F: FnMut(&[Tunnel]) + Send + 'static,
+    {
+        let created: Result<Vec<Tunnel>> = specs
+            .iter()
+            .map(|spec| ngrok.create_or_get(spec, ConflictPolicy::Recreate))
+            .collect();
+        let created = match created {
+            Ok(created) => created,
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+        };
+        on_tunnels(&created);
+        let specs = Arc::new(Mutex::new(specs));
+        let watch = Watch {
+            ngrok: ngrok.clone(),
+            child: Some(child),
+            specs: Arc::clone(&specs),
+            on_tunnels: Box::new(on_tunnels),
+            failures: HashMap::new(),
+        };
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle = thread::spawn(move || watch.run(stop_rx));
+        Ok(Supervisor {
+            ngrok: ngrok.clone(),
+            specs,
+            stop: Some(stop_tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Create a tunnel and keep it alive from now on.
+    pub fn add_tunnel(&self, spec: TunnelSpec) -> Result<Tunnel> {
+        let mut specs = self.specs.lock().unwrap();
+        let tunnel = self.ngrok.create_or_get(&spec, ConflictPolicy::Recreate)?;
+        specs.retain(|known| known.name != spec.name);
+        specs.push(spec);
+        Ok(tunnel)
+    }
+
+    /// Close a tunnel and stop restoring it.
+    pub fn remove_tunnel(&self, name: &str) -> Result<()> {
+        let mut specs = self.specs.lock().unwrap();
+        specs.retain(|spec| spec.name != name);
+        self.ngrok.delete(&format!("api/tunnels/{}", name))
+    }
+
+    pub fn ngrok(&self) -> &Ngrok {
+        &self.ngrok
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, Watch};
+    use crate::transport::{Method, Request, Response, Transport};
+    use crate::{Ngrok, Result, TunnelSpec, Tunnels};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// A v3 agent whose session is down: every create fails.
+    #[derive(Debug, Default)]
+    struct Offline {
+        creates: AtomicUsize,
+    }
+
+    impl Transport for Arc<Offline> {
+        fn send(&self, request: Request) -> Result<Response> {
+            match (request.method, request.url.path()) {
+                (Method::Get, "/api/status") => {
+                    Ok(Response::new(200, "OK", r#"{"version": "3.5.0"}"#))
+                }
+                (Method::Post, "/api/tunnels") => {
+                    self.creates.fetch_add(1, Ordering::SeqCst);
+                    Ok(Response::new(
+                        502,
+                        "Bad Gateway",
+                        r#"{"msg": "session closed"}"#,
+                    ))
+                }
+                _ => Ok(Response::new(404, "Not Found", "{}")),
+            }
+        }
+    }
+
+    #[test]
+    fn failed_restores_back_off() {
+        let agent = Arc::new(Offline::default());
+        let specs = Arc::new(Mutex::new(vec![TunnelSpec::http("erp", 8069)]));
+        let mut watch = Watch {
+            ngrok: Ngrok::with_transport(Arc::clone(&agent)),
+            child: None,
+            specs: Arc::clone(&specs),
+            on_tunnels: Box::new(|_| panic!("nothing was restored")),
+            failures: HashMap::new(),
+        };
+        let none = Tunnels {
+            tunnels: Vec::new(),
+        };
+        watch.restore(&none);
+        watch.restore(&none);
+        assert_eq!(agent.creates.load(Ordering::SeqCst), 1);
+        assert_eq!(watch.failures["erp"].0, 1);
+
+        // removed specs are not recreated, even once the backoff is over
+        watch.failures.clear();
+        specs.lock().unwrap().clear();
+        watch.restore(&none);
+        assert_eq!(agent.creates.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn restart_backoff() {
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+        assert_eq!(backoff(10), Duration::from_secs(60));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,262 @@
+//! TLS termination options, checked locally.
+//!
+//! The agent only reports a bad certificate once the tunnel is requested,
+//! with a message that does not say which file is wrong. `TlsTermination`
+//! reads the PEM files first: they must parse, the key must belong to the
+//! certificate and the certificates must be valid now.
+//!
+//! Matching the key needs no crypto: RSA and most EC private keys carry
+//! their public half, which is compared with the certificate's. Keys that
+//! do not (some PKCS#8 Ed25519 keys) are accepted unchecked.
+
+use log::debug;
+use rustls_pemfile::Item;
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use x509_parser::der_parser::ber::{BerObject, BerObjectContent, Class};
+use x509_parser::der_parser::parse_der;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use x509_parser::public_key::PublicKey;
+
+use crate::{canonical_path, ErrorKind, Result};
+
+const OID_RSA: &str = "1.2.840.113549.1.1.1";
+const OID_EC: &str = "1.2.840.10045.2.1";
+
+fn invalid<T>(path: &Path, reason: &str) -> Result<T> {
+    Err(ErrorKind::InvalidSpec(format!("{}: {}", path.display(), reason)).into())
+}
+
+/// A private key's public half, as found in a certificate.
+#[derive(Debug, PartialEq)]
+enum PublicPart {
+    Rsa { modulus: Vec<u8>, exponent: Vec<u8> },
+    Ec(Vec<u8>),
+    Unknown,
+}
+
+fn unsigned(bytes: &[u8]) -> Vec<u8> {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    bytes[start..].to_vec()
+}
+
+fn sequence<'a>(der: &'a [u8]) -> Option<Vec<BerObject<'a>>> {
+    let (_, object) = parse_der(der).ok()?;
+    object.as_sequence().ok().cloned()
+}
+
+/// The explicitly tagged `[1] BIT STRING` holding a public key.
+fn tagged_public_key(items: &[BerObject]) -> Option<Vec<u8>> {
+    let item = items
+        .iter()
+        .find(|item| item.class() == Class::ContextSpecific && item.tag().0 == 1)?;
+    let bits = match &item.content {
+        BerObjectContent::Tagged(_, _, inner) => inner.as_bitstring().ok()?.data.to_vec(),
+        _ => {
+            let (_, inner) = parse_der(item.as_slice().ok()?).ok()?;
+            inner.as_bitstring().ok()?.data.to_vec()
+        }
+    };
+    Some(bits)
+}
+
+/// RSAPrivateKey (PKCS#1): version, modulus, publicExponent, ...
+fn rsa_public(der: &[u8]) -> Option<PublicPart> {
+    let items = sequence(der)?;
+    Some(PublicPart::Rsa {
+        modulus: unsigned(items.get(1)?.as_slice().ok()?),
+        exponent: unsigned(items.get(2)?.as_slice().ok()?),
+    })
+}
+
+/// ECPrivateKey (SEC1): version, privateKey, [0] parameters, [1] publicKey.
+fn ec_public(der: &[u8]) -> Option<PublicPart> {
+    let items = sequence(der)?;
+    Some(tagged_public_key(&items).map_or(PublicPart::Unknown, PublicPart::Ec))
+}
+
+/// PrivateKeyInfo (PKCS#8): version, algorithm, privateKey, [1] publicKey.
+fn pkcs8_public(der: &[u8]) -> Option<PublicPart> {
+    let items = sequence(der)?;
+    let algorithm = items.get(1)?.as_sequence().ok()?.first()?.as_oid().ok()?;
+    let inner = items.get(2)?.as_slice().ok()?;
+    match algorithm.to_id_string().as_str() {
+        OID_RSA => rsa_public(inner),
+        OID_EC => ec_public(inner),
+        _ => Some(tagged_public_key(&items).map_or(PublicPart::Unknown, PublicPart::Ec)),
+    }
+}
+
+fn certificate_public(cert: &X509Certificate) -> Option<PublicPart> {
+    let spki = cert.public_key();
+    match spki.parsed().ok()? {
+        PublicKey::RSA(key) => Some(PublicPart::Rsa {
+            modulus: unsigned(key.modulus),
+            exponent: unsigned(key.exponent),
+        }),
+        _ => Some(PublicPart::Ec(spki.subject_public_key.data.to_vec())),
+    }
+}
+
+/// All certificates in the PEM file at `path`, DER encoded.
+fn read_certificates(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let text = match fs::read(path) {
+        Ok(text) => text,
+        Err(err) => return invalid(path, &err.to_string()),
+    };
+    let certs = rustls_pemfile::certs(&mut BufReader::new(&text[..]))
+        .map(|cert| cert.map(|cert| cert.as_ref().to_vec()))
+        .collect::<std::io::Result<Vec<_>>>();
+    match certs {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        Ok(_) => invalid(path, "no PEM certificate"),
+        Err(err) => invalid(path, &err.to_string()),
+    }
+}
+
+fn check_validity(path: &Path, cert: &X509Certificate, now: SystemTime) -> Result<()> {
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0);
+    let validity = cert.validity();
+    if now > validity.not_after.timestamp() {
+        return invalid(
+            path,
+            &format!("certificate expired on {}", validity.not_after),
+        );
+    }
+    if now < validity.not_before.timestamp() {
+        return invalid(
+            path,
+            &format!("certificate not valid before {}", validity.not_before),
+        );
+    }
+    Ok(())
+}
+
+/// Check that the first certificate in `crt` is valid at `now` and that
+/// `key` is its private key.
+fn check_pair(crt: &Path, key: &Path, now: SystemTime) -> Result<()> {
+    let certs = read_certificates(crt)?;
+    let cert = match X509Certificate::from_der(&certs[0]) {
+        Ok((_, cert)) => cert,
+        Err(err) => return invalid(crt, &err.to_string()),
+    };
+    check_validity(crt, &cert, now)?;
+
+    let text = match fs::read(key) {
+        Ok(text) => text,
+        Err(err) => return invalid(key, &err.to_string()),
+    };
+    let public = match rustls_pemfile::read_one(&mut BufReader::new(&text[..])) {
+        Ok(Some(Item::Pkcs1Key(der))) => rsa_public(der.secret_pkcs1_der()),
+        Ok(Some(Item::Sec1Key(der))) => ec_public(der.secret_sec1_der()),
+        Ok(Some(Item::Pkcs8Key(der))) => pkcs8_public(der.secret_pkcs8_der()),
+        Ok(_) => return invalid(key, "no PEM private key"),
+        Err(err) => return invalid(key, &err.to_string()),
+    };
+    match public {
+        None => invalid(key, "malformed private key"),
+        Some(PublicPart::Unknown) => {
+            debug!("{}: cannot tell whether it matches", key.display());
+            Ok(())
+        }
+        Some(public) if Some(&public) == certificate_public(&cert).as_ref() => Ok(()),
+        Some(_) => invalid(key, &format!("not the private key of {}", crt.display())),
+    }
+}
+
+/// Terminate TLS at the agent with `crt`/`key`, optionally requiring client
+/// certificates signed by `client_cas` (mutual TLS).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsTermination {
+    pub crt: PathBuf,
+    pub key: PathBuf,
+    pub client_cas: Option<PathBuf>,
+}
+
+impl TlsTermination {
+    pub fn new<C, K>(crt: C, key: K) -> Result<Self>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let absolute = |path: &Path| match canonical_path(path) {
+            Ok(path) => Ok(path),
+            Err(err) => invalid(path, &err.to_string()),
+        };
+        let termination = TlsTermination {
+            crt: absolute(crt.as_ref())?,
+            key: absolute(key.as_ref())?,
+            client_cas: None,
+        };
+        termination.check()?;
+        Ok(termination)
+    }
+
+    pub fn client_cas<P: AsRef<Path>>(mut self, path: P) -> Result<Self> {
+        self.client_cas = Some(match canonical_path(path.as_ref()) {
+            Ok(path) => path,
+            Err(err) => return invalid(path.as_ref(), &err.to_string()),
+        });
+        self.check()?;
+        Ok(self)
+    }
+
+    /// Check the files again, e.g. right before requesting the tunnel.
+    pub fn check(&self) -> Result<()> {
+        let now = SystemTime::now();
+        check_pair(&self.crt, &self.key, now)?;
+        if let Some(cas) = &self.client_cas {
+            for der in read_certificates(cas)? {
+                match X509Certificate::from_der(&der) {
+                    Ok((_, cert)) => check_validity(cas, &cert, now)?,
+                    Err(err) => return invalid(cas, &err.to_string()),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_pair, TlsTermination};
+    use crate::ErrorKind;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn fixture(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "tls", name]
+            .iter()
+            .collect()
+    }
+
+    #[test]
+    fn matching_pairs() {
+        let device = TlsTermination::new(fixture("device.crt"), fixture("device.key"))
+            .unwrap()
+            .client_cas(fixture("clients-ca.crt"))
+            .unwrap();
+        assert!(device.client_cas.unwrap().is_absolute());
+        assert!(TlsTermination::new(fixture("ec.crt"), fixture("ec.key")).is_ok());
+        // valid back then
+        let in_2020 = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        assert!(check_pair(&fixture("expired.crt"), &fixture("expired.key"), in_2020).is_ok());
+    }
+
+    #[test]
+    fn rejected_pairs() {
+        let err = TlsTermination::new(fixture("device.crt"), fixture("ec.key")).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidSpec(_)));
+        let err = TlsTermination::new(fixture("device.crt"), fixture("expired.key")).unwrap_err();
+        assert!(err.to_string().contains("not the private key"));
+        let err = TlsTermination::new(fixture("expired.crt"), fixture("expired.key")).unwrap_err();
+        assert!(err.to_string().contains("expired"));
+        assert!(TlsTermination::new(fixture("device.key"), fixture("device.key")).is_err());
+        assert!(TlsTermination::new(fixture("device.crt"), fixture("missing.key")).is_err());
+    }
+}
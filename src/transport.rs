@@ -0,0 +1,280 @@
+//! HTTP transport used to reach the agent API.
+//!
+//! `Ngrok` only needs "request in, status + headers + body out". The default
+//! `UreqTransport` keeps a `ureq::Agent` around so connections are reused;
+//! any other client (or an in-memory fake) can be plugged in with
+//! `Ngrok::with_transport`.
+
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use log::debug;
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::io::{self, Cursor, Read};
+use std::time::Duration;
+use url::Url;
+
+use crate::{ErrorKind, Result};
+
+/// How much of a body is kept around for error reports.
+const DIAGNOSTIC_LIMIT: usize = 64 * 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Delete,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Delete => "DELETE",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub url: Url,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub status_text: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    /// Build a response from an in-memory body, e.g. in a test double.
+    pub fn new<B>(status: u16, status_text: &str, body: B) -> Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        Response {
+            status,
+            status_text: status_text.to_owned(),
+            headers: Vec::new(),
+            body: Box::new(Cursor::new(body.into())),
+        }
+    }
+
+    /// First value of the header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body, decompressed according to `Content-Encoding` when the
+    /// transport left that to us.
+    fn decoded(self) -> Result<Box<dyn Read + Send>> {
+        let encoding = self
+            .header("Content-Encoding")
+            .map(|encoding| encoding.trim().to_ascii_lowercase());
+        match encoding.as_deref() {
+            None | Some("") | Some("identity") => Ok(self.body),
+            Some("gzip") | Some("x-gzip") => Ok(Box::new(MultiGzDecoder::new(self.body))),
+            Some("deflate") => Ok(Box::new(ZlibDecoder::new(self.body))),
+            Some(other) => Err(ErrorKind::MalformedResponse(
+                format!("unsupported content encoding {}", other),
+                String::new(),
+            )
+            .into()),
+        }
+    }
+
+    /// Deserialize the body as it streams in, whatever its length or
+    /// encoding. On failure the error carries what was read of the body.
+    pub fn json<T>(self) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let mut reader = Recorder {
+            inner: self.decoded()?,
+            seen: Vec::new(),
+        };
+        match serde_json::from_reader::<_, T>(&mut reader) {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                // read on so the report shows the body, up to what is kept
+                let rest = DIAGNOSTIC_LIMIT.saturating_sub(reader.seen.len()) as u64;
+                let _ = io::copy(&mut (&mut reader).take(rest), &mut io::sink());
+                let body = String::from_utf8_lossy(&reader.seen).into_owned();
+                debug!("RAW: {}", body);
+                Err(ErrorKind::MalformedResponse(err.to_string(), body).into())
+            }
+        }
+    }
+
+    /// The body as text, for error reports.
+    pub fn text(self) -> String {
+        let mut bytes = Vec::new();
+        if let Ok(body) = self.decoded() {
+            let _ = body.take(DIAGNOSTIC_LIMIT as u64).read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// Reader keeping a copy of the first `DIAGNOSTIC_LIMIT` bytes read.
+struct Recorder<R> {
+    inner: R,
+    seen: Vec<u8>,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let keep = n.min(DIAGNOSTIC_LIMIT.saturating_sub(self.seen.len()));
+        self.seen.extend_from_slice(&buf[..keep]);
+        Ok(n)
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("status_text", &self.status_text)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+/// Sends requests to the agent. Non-2xx answers are responses, not errors:
+/// only failing to get an answer at all is an error.
+pub trait Transport: fmt::Debug + Send + Sync {
+    fn send(&self, request: Request) -> Result<Response>;
+}
+
+/// Default transport, reusing the connections of a `ureq::Agent`.
+#[derive(Debug, Clone)]
+pub struct UreqTransport {
+    agent: ureq::Agent,
+}
+
+impl Default for UreqTransport {
+    fn default() -> Self {
+        Self::with_timeouts(CONNECT_TIMEOUT, TIMEOUT)
+    }
+}
+
+impl UreqTransport {
+    pub fn with_timeouts(connect: Duration, overall: Duration) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(connect)
+            .timeout(overall)
+            .build();
+        UreqTransport { agent }
+    }
+
+    /// Use an agent configured by the caller, e.g. with a proxy.
+    pub fn from_agent(agent: ureq::Agent) -> Self {
+        UreqTransport { agent }
+    }
+}
+
+impl Transport for UreqTransport {
+    fn send(&self, request: Request) -> Result<Response> {
+        let mut req = self
+            .agent
+            .request_url(request.method.as_str(), &request.url);
+        for (name, value) in &request.headers {
+            req = req.set(name, value);
+        }
+        let result = match request.body {
+            Some(body) => req.send_bytes(&body),
+            None => req.call(),
+        };
+        let resp = match result {
+            Ok(resp) => resp,
+            Err(ureq::Error::Status(_, resp)) => resp,
+            Err(err) => return Err(Box::new(err).into()),
+        };
+        let headers = resp
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = resp.header(&name)?.to_owned();
+                Some((name, value))
+            })
+            .collect();
+        Ok(Response {
+            status: resp.status(),
+            status_text: resp.status_text().to_owned(),
+            headers,
+            body: Box::new(resp.into_reader()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Request, Response, Transport, DIAGNOSTIC_LIMIT};
+    use crate::{ErrorKind, Ngrok, Result, Tunnels};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::{self, Read, Write};
+
+    #[derive(Debug)]
+    struct Fake;
+
+    impl Transport for Fake {
+        fn send(&self, request: Request) -> Result<Response> {
+            let body = r#"{"tunnels": [], "uri": "/api/tunnels"}"#;
+            match request.url.path() {
+                "/api/tunnels" => Ok(Response::new(200, "OK", body)),
+                _ => Ok(Response::new(404, "Not Found", "{}")),
+            }
+        }
+    }
+
+    #[test]
+    fn fake_transport() {
+        let ngrok = Ngrok::with_transport(Fake);
+        let tunnels = ngrok.get::<Tunnels>("api/tunnels").unwrap();
+        assert!(tunnels.tunnels.is_empty());
+        let err = ngrok.delete("api/tunnels/erp").unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Server(404, _)));
+    }
+
+    #[test]
+    fn gzip_body_without_length() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(br#"{"tunnels": []}"#).unwrap();
+        let mut resp = Response::new(200, "OK", encoder.finish().unwrap());
+        resp.headers
+            .push(("Content-Encoding".to_owned(), "gzip".to_owned()));
+        let tunnels: Tunnels = resp.json().unwrap();
+        assert!(tunnels.tunnels.is_empty());
+    }
+
+    #[test]
+    fn malformed_body_is_kept() {
+        let resp = Response::new(200, "OK", "<html>bad gateway</html>");
+        let err = resp.json::<Tunnels>().unwrap_err();
+        match err.kind() {
+            ErrorKind::MalformedResponse(_, body) => assert_eq!(body, "<html>bad gateway</html>"),
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+
+        // an endless body is only read as far as it is kept
+        let mut resp = Response::new(200, "OK", "");
+        resp.body = Box::new(io::Cursor::new("<html>").chain(io::repeat(b'x')));
+        let err = resp.json::<Tunnels>().unwrap_err();
+        match err.kind() {
+            ErrorKind::MalformedResponse(_, body) => assert_eq!(body.len(), DIAGNOSTIC_LIMIT),
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! Pre-flight check of a tunnel's upstream.
+//!
+//! A tunnel to a port nobody listens on is created fine, and visitors get
+//! a 502 from the agent. `UpstreamCheck` connects to the upstream first
+//! (and optionally asks a health endpoint), retrying until a deadline, so
+//! `create_tunnel` fails with `ErrorKind::UpstreamUnavailable` instead.
+//!
+//! Local https services usually have a self-signed certificate, so the
+//! health endpoint of a loopback upstream is asked without verifying it.
+
+use log::debug;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use std::net::{IpAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Addr, Error, ErrorKind, Result};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Accepts any certificate, still checking the handshake signatures.
+#[derive(Debug)]
+struct AnyCertificate(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+/// TLS settings for a loopback upstream, whose certificate is not checked.
+fn loopback_tls() -> Option<Arc<ClientConfig>> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let verifier = AnyCertificate(provider.signature_verification_algorithms);
+    let config = ClientConfig::builder_with_provider(Arc::clone(&provider) as Arc<CryptoProvider>)
+        .with_safe_default_protocol_versions()
+        .ok()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Some(Arc::new(config))
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// How to check an upstream before tunnelling to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamCheck {
+    /// Path answered with a 2xx or 3xx when the service is up, e.g.
+    /// `/healthz`. Only the TCP connection is checked when unset.
+    pub health_path: Option<String>,
+    /// How long to keep retrying, e.g. while the service starts.
+    pub deadline: Duration,
+}
+
+impl Default for UpstreamCheck {
+    fn default() -> Self {
+        UpstreamCheck {
+            health_path: None,
+            deadline: Duration::from_secs(5),
+        }
+    }
+}
+
+impl UpstreamCheck {
+    pub fn health_path<P: Into<String>>(mut self, path: P) -> Self {
+        self.health_path = Some(path.into());
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// The health endpoint of `addr`, if there is one to ask.
+    fn health_url(&self, addr: &Addr) -> Option<String> {
+        let path = self.health_path.as_ref()?;
+        let path = path.trim_start_matches('/');
+        match addr {
+            Addr::Url(url) => url.join(path).ok().map(String::from),
+            Addr::File(_) => None,
+            _ => {
+                let (host, port) = addr.host_port()?;
+                Some(format!("http://{}:{}/{}", host, port, path))
+            }
+        }
+    }
+
+    /// One attempt, taking about `remaining` at most; the error says what
+    /// failed.
+    fn attempt(&self, addr: &Addr, remaining: Duration) -> std::result::Result<(), String> {
+        if let Addr::File(path) = addr {
+            if path.is_dir() {
+                return Ok(());
+            }
+            return Err(format!("{}: no such directory", path.display()));
+        }
+        let socket_addrs = addr.socket_addrs();
+        if socket_addrs.is_empty() {
+            return Err(format!("could not resolve {}", addr));
+        }
+        let mut connected = Err(String::new());
+        for socket_addr in &socket_addrs {
+            connected = TcpStream::connect_timeout(socket_addr, CONNECT_TIMEOUT)
+                .map(|_| ())
+                .map_err(|err| format!("connect to {}: {}", socket_addr, err));
+            if connected.is_ok() {
+                break;
+            }
+        }
+        connected?;
+        if let Some(url) = self.health_url(addr) {
+            let mut builder = ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout(remaining.max(CONNECT_TIMEOUT))
+                .redirects(0);
+            let loopback = addr.host_port().is_some_and(|(host, _)| is_loopback(&host));
+            if let (true, Some(tls)) = (loopback, loopback_tls()) {
+                builder = builder.tls_config(tls);
+            }
+            let agent = builder.build();
+            match agent.get(&url).call() {
+                // without redirects, 3xx answers are not errors either
+                Ok(resp) => debug!("GET {}: {}", url, resp.status()),
+                Err(ureq::Error::Status(status, resp)) => {
+                    return Err(format!("GET {}: {} {}", url, status, resp.status_text()))
+                }
+                Err(err) => return Err(format!("GET {}: {}", url, err)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Check `addr` until it answers or the deadline passes.
+    pub fn run(&self, addr: &Addr) -> Result<()> {
+        let start = Instant::now();
+        let mut tried = Vec::new();
+        loop {
+            match self.attempt(addr, self.deadline.saturating_sub(start.elapsed())) {
+                Ok(()) => return Ok(()),
+                Err(failure) => {
+                    debug!("upstream {} not ready: {}", addr, failure);
+                    // keep one line per distinct failure, in order
+                    if tried.last() != Some(&failure) {
+                        tried.push(failure);
+                    }
+                }
+            }
+            if start.elapsed() + RETRY_INTERVAL > self.deadline {
+                break;
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+        Err(Error::from(ErrorKind::UpstreamUnavailable(
+            addr.to_string(),
+            tried,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UpstreamCheck;
+    use crate::{Addr, ErrorKind};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn checks_upstreams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = Addr::from(listener.local_addr().unwrap());
+        let check = UpstreamCheck::default().deadline(Duration::from_millis(100));
+        assert!(check.run(&addr).is_ok());
+
+        let server = thread::spawn(move || {
+            // answer the first connection sending a request; plain connection
+            // checks send nothing
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 1024];
+                if stream.read(&mut request).unwrap_or(0) > 0 {
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+                    );
+                    break;
+                }
+            }
+        });
+        let err = check
+            .clone()
+            .deadline(Duration::ZERO)
+            .health_path("/healthz")
+            .run(&addr)
+            .unwrap_err();
+        server.join().unwrap();
+        match err.kind() {
+            ErrorKind::UpstreamUnavailable(_, tried) => {
+                assert_eq!(tried.len(), 1);
+                assert!(tried[0].contains("/healthz: 503"), "{}", tried[0]);
+            }
+            kind => panic!("unexpected error: {:?}", kind),
+        }
+
+        // nothing listens there any more
+        let err = check.run(&addr).unwrap_err();
+        assert!(err.to_string().contains("connect to"), "{}", err);
+    }
+
+    #[test]
+    fn self_signed_loopback_upstreams() {
+        let fixture = |name: &str| {
+            let path = [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "tls", name];
+            std::fs::read(path.iter().collect::<std::path::PathBuf>()).unwrap()
+        };
+        let certs = rustls_pemfile::certs(&mut &fixture("device.crt")[..])
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        let key = rustls_pemfile::private_key(&mut &fixture("device.key")[..])
+            .unwrap()
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let config = Arc::new(config);
+            for stream in listener.incoming().flatten() {
+                let conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+                let mut tls = rustls::StreamOwned::new(conn, stream);
+                let mut request = [0; 1024];
+                // plain connection checks send nothing and fail the handshake
+                if tls.read(&mut request).unwrap_or(0) > 0 {
+                    let _ = tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+                    tls.conn.send_close_notify();
+                    let _ = tls.flush();
+                    break;
+                }
+            }
+        });
+        let addr: Addr = format!("https://localhost:{}", port).parse().unwrap();
+        let check = UpstreamCheck::default()
+            .deadline(Duration::from_secs(2))
+            .health_path("/healthz");
+        check.run(&addr).unwrap();
+        server.join().unwrap();
+    }
+}
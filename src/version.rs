@@ -0,0 +1,146 @@
+//! Which ngrok agent we are talking to.
+
+use log::{debug, warn};
+use semver::Version;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::process;
+
+use crate::{find_file_in_path, Error, ErrorKind, Ngrok, Result, ResultExt};
+
+/// Major version of the agent, which decides the API models to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgentVersion {
+    V2,
+    V3,
+}
+
+impl AgentVersion {
+    pub fn from_version(version: &Version) -> Result<Self> {
+        match version.major {
+            2 => Ok(AgentVersion::V2),
+            3 => Ok(AgentVersion::V3),
+            _ => Err(ErrorKind::UnsupportedAgent(version.to_string()).into()),
+        }
+    }
+}
+
+/// Whatever the agent answers on `api/status`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AgentStatus {
+    #[serde(flatten)]
+    pub raw: Map<String, Value>,
+}
+
+impl AgentStatus {
+    /// The agent version, if the status reports one.
+    pub fn version(&self) -> Option<Version> {
+        find_version(&self.raw)
+    }
+}
+
+fn find_version(map: &Map<String, Value>) -> Option<Version> {
+    if let Some(Value::String(version)) = map.get("version") {
+        if let Ok(version) = parse_version(version) {
+            return Some(version);
+        }
+    }
+    map.values().find_map(|value| match value {
+        Value::Object(map) => find_version(map),
+        _ => None,
+    })
+}
+
+/// Parse `2.3.40`, `v3.1` or the `ngrok version 3.3.1` line the binary prints.
+pub fn parse_version(text: &str) -> Result<Version> {
+    let word = text
+        .split_whitespace()
+        .last()
+        .ok_or_else(|| Error::from(format!("no version in {:?}", text)))?;
+    let word = word.trim_start_matches('v');
+    let padded = match word.split('-').next().unwrap_or("").matches('.').count() {
+        0 => format!("{}.0.0", word),
+        1 => format!("{}.0", word),
+        _ => word.to_owned(),
+    };
+    Version::parse(&padded).chain_err(|| format!("malformed version {:?}", text))
+}
+
+impl Ngrok {
+    pub fn status(&self) -> Result<AgentStatus> {
+        self.get::<AgentStatus>("api/status")
+    }
+
+    /// Run `<path> version` and parse what it prints.
+    pub fn binary_version<P>(path: P) -> Result<Version>
+    where
+        P: AsRef<Path>,
+    {
+        let output = process::Command::new(path.as_ref())
+            .arg("version")
+            .output()
+            .chain_err(|| format!("could not run {}", path.as_ref().display()))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        debug!("{} version: {}", path.as_ref().display(), stdout.trim());
+        parse_version(stdout.trim())
+    }
+
+    /// Detect the agent version, from `api/status` when the agent reports it
+    /// there, else from the ngrok binary found in `PATH`. Cached once known.
+    pub fn agent_version(&self) -> Result<AgentVersion> {
+        if let Some(version) = self.agent_version.get() {
+            return Ok(*version);
+        }
+        let version = match self.status().ok().and_then(|status| status.version()) {
+            Some(version) => version,
+            None => match find_file_in_path(self.exe_name()) {
+                Some(path) => Self::binary_version(path)?,
+                None => return Err("could not detect the ngrok agent version".into()),
+            },
+        };
+        debug!("ngrok agent version: {}", version);
+        let version = AgentVersion::from_version(&version)?;
+        let _ = self.agent_version.set(version);
+        Ok(version)
+    }
+
+    /// The API models to talk to the agent with: those of the detected
+    /// version, or v2 when detection fails.
+    pub fn api_version(&self) -> AgentVersion {
+        self.agent_version().unwrap_or_else(|err| {
+            warn!("{}, assuming a v2 agent", err);
+            AgentVersion::V2
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_version, AgentStatus, AgentVersion};
+    use semver::Version;
+    use serde_json::json;
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!(
+            parse_version("ngrok version 2.3.40").unwrap(),
+            Version::new(2, 3, 40)
+        );
+        assert_eq!(parse_version("v3.1").unwrap(), Version::new(3, 1, 0));
+        assert!(parse_version("ngrok version").is_err());
+        assert_eq!(
+            AgentVersion::from_version(&Version::new(3, 3, 1)).unwrap(),
+            AgentVersion::V3
+        );
+        assert!(AgentVersion::from_version(&Version::new(1, 7, 0)).is_err());
+    }
+
+    #[test]
+    fn status_version() {
+        let status: AgentStatus =
+            serde_json::from_value(json!({"status": {"version": "2.3.40", "region": "eu"}}))
+                .unwrap();
+        assert_eq!(status.version(), Some(Version::new(2, 3, 40)));
+    }
+}
@@ -0,0 +1,237 @@
+//! Background polling of tunnel metrics.
+//!
+//! `MetricsWatcher` polls `api/tunnels` on an interval, keeps the last few
+//! `Metrics` of every tunnel and sends what changed since the previous poll
+//! down a channel.
+
+use log::{debug, error};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Metrics, Ngrok, Tunnels};
+
+/// Metrics of one tunnel at one poll.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetricsSample {
+    pub at: Instant,
+    pub metrics: Metrics,
+}
+
+/// What changed on one tunnel between two polls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsDelta {
+    pub tunnel: String,
+    pub elapsed: Duration,
+    pub new_conns: u64,
+    pub new_requests: u64,
+    pub open_conns: f64,
+}
+
+impl MetricsDelta {
+    /// New connections per second over the polling interval.
+    pub fn conns_per_sec(&self) -> f64 {
+        per_sec(self.new_conns, self.elapsed)
+    }
+
+    /// New HTTP requests per second over the polling interval.
+    pub fn requests_per_sec(&self) -> f64 {
+        per_sec(self.new_requests, self.elapsed)
+    }
+}
+
+fn per_sec(count: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        count as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// A counter that went down was reset (agent restarted, tunnel recreated),
+/// so everything it counts now is new.
+fn increase(previous: u64, current: u64) -> u64 {
+    if current >= previous {
+        current - previous
+    } else {
+        current
+    }
+}
+
+/// Bounded per-tunnel time series.
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    last_poll: Option<Instant>,
+    series: HashMap<String, VecDeque<MetricsSample>>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            capacity: capacity.max(1),
+            last_poll: None,
+            series: HashMap::new(),
+        }
+    }
+
+    /// Record a snapshot taken at `now` and return the deltas of the
+    /// tunnels that were already known. Tunnels that vanished are forgotten.
+    pub fn record(&mut self, now: Instant, tunnels: &Tunnels) -> Vec<MetricsDelta> {
+        let elapsed = self
+            .last_poll
+            .map(|last| now.duration_since(last))
+            .unwrap_or_default();
+        self.last_poll = Some(now);
+        self.series
+            .retain(|name, _| tunnels.tunnels.iter().any(|t| &t.name == name));
+
+        let mut deltas = Vec::new();
+        for tunnel in &tunnels.tunnels {
+            let series = self.series.entry(tunnel.name.clone()).or_default();
+            let current = tunnel.metrics;
+            if let Some(previous) = series.back() {
+                let previous = previous.metrics;
+                deltas.push(MetricsDelta {
+                    tunnel: tunnel.name.clone(),
+                    elapsed,
+                    new_conns: increase(previous.conns.count, current.conns.count),
+                    new_requests: increase(previous.http.count, current.http.count),
+                    open_conns: current.conns.gauge,
+                });
+            }
+            if series.len() == self.capacity {
+                series.pop_front();
+            }
+            series.push_back(MetricsSample {
+                at: now,
+                metrics: current,
+            });
+        }
+        deltas
+    }
+
+    /// The recorded samples of `tunnel`, oldest first.
+    pub fn series(&self, tunnel: &str) -> Vec<MetricsSample> {
+        self.series
+            .get(tunnel)
+            .map(|series| series.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn tunnels(&self) -> Vec<String> {
+        self.series.keys().cloned().collect()
+    }
+}
+
+/// Polls the agent from a background thread until dropped.
+#[derive(Debug)]
+pub struct MetricsWatcher {
+    history: Arc<Mutex<History>>,
+    stop: Option<Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl MetricsWatcher {
+    /// Start polling every `interval`, keeping at most `capacity` samples
+    /// per tunnel. The deltas of each poll are sent on the returned channel;
+    /// polling goes on even if the receiver is dropped.
+    pub fn start(
+        ngrok: Ngrok,
+        interval: Duration,
+        capacity: usize,
+    ) -> (Self, Receiver<Vec<MetricsDelta>>) {
+        let history = Arc::new(Mutex::new(History::new(capacity)));
+        let (updates_tx, updates_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let shared = Arc::clone(&history);
+        let handle = thread::spawn(move || loop {
+            match ngrok.get::<Tunnels>("api/tunnels") {
+                Ok(tunnels) => {
+                    let deltas = shared.lock().unwrap().record(Instant::now(), &tunnels);
+                    if !deltas.is_empty() && updates_tx.send(deltas).is_err() {
+                        debug!("metrics receiver dropped");
+                    }
+                }
+                Err(err) => error!("could not poll metrics: {}", err),
+            }
+            match stop_rx.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                _ => break,
+            }
+        });
+        let watcher = MetricsWatcher {
+            history,
+            stop: Some(stop_tx),
+            handle: Some(handle),
+        };
+        (watcher, updates_rx)
+    }
+
+    /// The recorded samples of `tunnel`, oldest first.
+    pub fn series(&self, tunnel: &str) -> Vec<MetricsSample> {
+        self.history.lock().unwrap().series(tunnel)
+    }
+
+    pub fn tunnels(&self) -> Vec<String> {
+        self.history.lock().unwrap().tunnels()
+    }
+}
+
+impl Drop for MetricsWatcher {
+    fn drop(&mut self) {
+        // dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::History;
+    use crate::{tunnel_fixture, Tunnels};
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    fn snapshot(conns: u64, requests: u64) -> Tunnels {
+        let mut tunnel = tunnel_fixture(
+            "erp",
+            "https",
+            "https://abcd.ngrok.io",
+            "http://localhost:8069",
+        );
+        tunnel["metrics"]["conns"]["count"] = json!(conns);
+        tunnel["metrics"]["conns"]["gauge"] = json!(2);
+        tunnel["metrics"]["http"]["count"] = json!(requests);
+        serde_json::from_value(json!({ "tunnels": [tunnel] })).unwrap()
+    }
+
+    #[test]
+    fn history_deltas() {
+        let mut history = History::new(2);
+        let start = Instant::now();
+        assert!(history.record(start, &snapshot(3, 10)).is_empty());
+
+        let deltas = history.record(start + Duration::from_secs(2), &snapshot(5, 20));
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].new_conns, 2);
+        assert_eq!(deltas[0].new_requests, 10);
+        assert_eq!(deltas[0].requests_per_sec(), 5.0);
+
+        // counters reset after an agent restart
+        let deltas = history.record(start + Duration::from_secs(4), &snapshot(1, 4));
+        assert_eq!(deltas[0].new_conns, 1);
+        assert_eq!(deltas[0].new_requests, 4);
+        let series = history.series("erp");
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[1].at, start + Duration::from_secs(4));
+
+        history.record(start + Duration::from_secs(6), &Tunnels { tunnels: vec![] });
+        assert!(history.series("erp").is_empty());
+    }
+}